@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use crate::protocol::AssetKind;
+
+const MANIFEST: &str = "manifest.json";
+
+// SPIFFS object names are limited to 32 bytes, leave room for ".tmp"
+const MAX_NAME_LEN: usize = 24;
+pub const MAX_ASSET_SIZE: usize = 1024 * 1024;
+
+/// Flat file storage the asset store is built on.
+pub trait Storage {
+    type Reader: Read;
+
+    fn open(&self, name: &str) -> io::Result<Self::Reader>;
+    /// Replaces `name` with `data`.
+    fn write(&mut self, name: &str, data: &[u8]) -> io::Result<()>;
+    fn remove(&mut self, name: &str) -> io::Result<()>;
+}
+
+/// Files in a directory, i.e. the SPIFFS mount point on the device.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Storage for FsStorage {
+    type Reader = std::fs::File;
+
+    fn open(&self, name: &str) -> io::Result<Self::Reader> {
+        std::fs::File::open(self.root.join(name))
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        // SPIFFS can't rename over an existing file, so the old one is removed
+        // first. The manifest hash catches a file that was cut short.
+        let tmp = self.root.join(format!("{name}.tmp"));
+        std::fs::write(&tmp, data)?;
+        match std::fs::remove_file(self.root.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::rename(tmp, self.root.join(name))
+    }
+
+    fn remove(&mut self, name: &str) -> io::Result<()> {
+        std::fs::remove_file(self.root.join(name))
+    }
+}
+
+#[derive(Default)]
+pub struct MemStorage {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl Storage for MemStorage {
+    type Reader = io::Cursor<Vec<u8>>;
+
+    fn open(&self, name: &str) -> io::Result<Self::Reader> {
+        self.files
+            .get(name)
+            .map(|data| io::Cursor::new(data.clone()))
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.files.insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> io::Result<()> {
+        self.files
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssetEntry {
+    pub name: String,
+    pub kind: AssetKind,
+    pub size: usize,
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    assets: Vec<AssetEntry>,
+    active: HashMap<String, String>,
+}
+
+/// What `AssetStore::put` checks before it writes anything: the name and
+/// the size.
+pub fn check(name: &str, data: &[u8]) -> anyhow::Result<()> {
+    if name.is_empty()
+        || name.len() > MAX_NAME_LEN
+        || name == MANIFEST
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        anyhow::bail!("Invalid asset name: {:?}", name);
+    }
+    if data.len() > MAX_ASSET_SIZE {
+        anyhow::bail!(
+            "Asset is too large: {} KB (max {} KB)",
+            data.len() / 1024,
+            MAX_ASSET_SIZE / 1024
+        );
+    }
+    Ok(())
+}
+
+/// Named assets plus a manifest with their kind, size and hash.
+///
+/// Each kind can have one active asset, e.g. the background shown at boot.
+/// Without one the built-in default is used.
+pub struct AssetStore<S: Storage> {
+    storage: S,
+    manifest: Manifest,
+}
+
+impl<S: Storage> AssetStore<S> {
+    pub fn new(storage: S) -> Self {
+        let manifest = storage
+            .open(MANIFEST)
+            .map_err(anyhow::Error::from)
+            .and_then(|r| Ok(serde_json::from_reader(r)?))
+            .unwrap_or_else(|e| {
+                log::warn!("No asset manifest, starting empty: {:?}", e);
+                Manifest::default()
+            });
+        Self { storage, manifest }
+    }
+
+    pub fn list(&self) -> &[AssetEntry] {
+        &self.manifest.assets
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&AssetEntry> {
+        self.manifest.assets.iter().find(|a| a.name == name)
+    }
+
+    pub fn put(&mut self, name: &str, kind: AssetKind, data: &[u8]) -> anyhow::Result<()> {
+        check(name, data)?;
+
+        self.storage.write(name, data).map_err(|e| {
+            if e.raw_os_error() == Some(28) {
+                // ENOSPC
+                anyhow::anyhow!("Not enough space to store {} KB", data.len() / 1024)
+            } else {
+                anyhow::anyhow!("Failed to store {}: {}", name, e)
+            }
+        })?;
+
+        let entry = AssetEntry {
+            name: name.to_string(),
+            kind,
+            size: data.len(),
+            sha256: hex(&sha2::Sha256::digest(data)),
+        };
+        self.manifest.assets.retain(|a| a.name != name);
+        self.manifest.assets.push(entry);
+        self.save_manifest()?;
+        log::info!("Asset {} saved, {} bytes", name, data.len());
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown asset {}", name))?;
+        let mut data = Vec::with_capacity(entry.size);
+        self.storage.open(name)?.read_to_end(&mut data)?;
+        if hex(&sha2::Sha256::digest(&data)) != entry.sha256 {
+            anyhow::bail!("Asset {} is corrupted", name);
+        }
+        Ok(data)
+    }
+
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<()> {
+        self.manifest.assets.retain(|a| a.name != name);
+        self.manifest.active.retain(|_, v| v != name);
+        self.save_manifest()?;
+        match self.storage.remove(name) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes every asset and selection, the built-in ones are used again.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        let names: Vec<String> = self.list().iter().map(|a| a.name.clone()).collect();
+        self.manifest = Manifest::default();
+        self.save_manifest()?;
+        for name in names {
+            match self.storage.remove(&name) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    log::warn!("Failed to remove asset {}: {:?}", name, e)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Selects the asset used for `kind`, `None` goes back to the built-in one.
+    pub fn set_active(&mut self, kind: AssetKind, name: Option<&str>) -> anyhow::Result<()> {
+        let key = kind_key(kind);
+        match name {
+            Some(name) => {
+                let entry = self
+                    .entry(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown asset {}", name))?;
+                if entry.kind != kind {
+                    anyhow::bail!("Asset {} is a {:?}, not a {:?}", name, entry.kind, kind);
+                }
+                self.manifest.active.insert(key, name.to_string());
+            }
+            None => {
+                self.manifest.active.remove(&key);
+            }
+        }
+        self.save_manifest()
+    }
+
+    /// Loads the active asset for `kind`, if it is set and intact.
+    pub fn active(&self, kind: AssetKind) -> Option<Vec<u8>> {
+        let name = self.manifest.active.get(&kind_key(kind))?;
+        self.get(name)
+            .map_err(|e| log::error!("Failed to load active {:?}: {:?}", kind, e))
+            .ok()
+    }
+
+    fn save_manifest(&mut self) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(&self.manifest)?;
+        self.storage.write(MANIFEST, &data)?;
+        Ok(())
+    }
+}
+
+fn kind_key(kind: AssetKind) -> String {
+    format!("{:?}", kind).to_lowercase()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_asset_store() {
+    let mut store = AssetStore::new(MemStorage::default());
+    assert!(store.active(AssetKind::Hello).is_none());
+
+    store.put("beep.wav", AssetKind::Hello, b"beep").unwrap();
+    store.put("boop.wav", AssetKind::Hello, b"boop").unwrap();
+    assert!(store
+        .set_active(AssetKind::Background, Some("beep.wav"))
+        .is_err());
+    store
+        .set_active(AssetKind::Hello, Some("boop.wav"))
+        .unwrap();
+    assert_eq!(store.active(AssetKind::Hello).unwrap(), b"boop");
+
+    // the manifest survives a reload
+    let store = AssetStore::new(store.storage);
+    assert_eq!(store.list().len(), 2);
+    assert_eq!(store.active(AssetKind::Hello).unwrap(), b"boop");
+
+    let mut store = store;
+    store.storage.write("boop.wav", b"bad").unwrap();
+    assert!(store.get("boop.wav").is_err());
+    store.remove("boop.wav").unwrap();
+    assert!(store.active(AssetKind::Hello).is_none());
+    assert!(store.put("../x", AssetKind::Hello, b"x").is_err());
+}
+
+#[test]
+fn test_asset_clear() {
+    let mut store = AssetStore::new(MemStorage::default());
+    store.put("beep.wav", AssetKind::Hello, b"beep").unwrap();
+    store
+        .put("bg.gif", AssetKind::Background, b"GIF89a")
+        .unwrap();
+    store
+        .set_active(AssetKind::Background, Some("bg.gif"))
+        .unwrap();
+
+    store.clear().unwrap();
+    assert!(store.list().is_empty());
+    assert!(store.active(AssetKind::Background).is_none());
+    assert_eq!(store.storage.files.len(), 1);
+
+    let store = AssetStore::new(store.storage);
+    assert!(store.list().is_empty());
+}
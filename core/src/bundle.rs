@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    networks::{KnownNetwork, Networks, WifiAuth},
+    protocol::{base64_bytes, base64_opt_bytes, AssetKind},
+    settings::{self, Def, Value},
+};
+
+pub const VERSION: u32 = 1;
+
+/// A stored asset, as written by the transfer sub-protocol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundleAsset {
+    pub name: String,
+    pub kind: AssetKind,
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+    /// Used for `kind` once imported.
+    #[serde(default)]
+    pub active: bool,
+}
+
+/// What a bundle sets up. Anything left out stays as it is.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Added to the saved networks, or replacing them with `replace_networks`.
+    /// One without a password keeps the saved network's, as exported bundles
+    /// have none. Open networks need `"auth":"open"`.
+    #[serde(default)]
+    pub networks: Vec<KnownNetwork>,
+    #[serde(default)]
+    pub replace_networks: bool,
+    /// Keys from `settings::SCHEMA`.
+    #[serde(default)]
+    pub settings: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub assets: Vec<BundleAsset>,
+}
+
+/// A configuration bundle, as JSON or msgpack. The msgpack form can also be
+/// base64 encoded, which is how it fits in a QR code or a console line.
+///
+/// `config` is kept as the encoded bytes (a JSON or msgpack [`Config`]), so
+/// the signature covers exactly what was signed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    #[serde(with = "base64_bytes")]
+    pub config: Vec<u8>,
+    /// ed25519 signature over `config`, required when the firmware is built
+    /// with `ECHOKIT_BUNDLE_PUBKEY`
+    #[serde(default, with = "base64_opt_bytes")]
+    pub signature: Option<Vec<u8>>,
+}
+
+/// Validated bundle contents. The whole bundle is checked before anything is
+/// stored, so a bad bundle changes nothing. Storing can still fail, e.g. when
+/// assets don't fit, which is why they are written last.
+#[derive(Debug)]
+pub struct Plan {
+    pub networks: Option<Networks>,
+    pub settings: Vec<(&'static Def, Value)>,
+    pub assets: Vec<BundleAsset>,
+    /// Settings this firmware doesn't know, e.g. from a newer bundle.
+    pub skipped: Vec<String>,
+}
+
+/// What an import changed, sent back as JSON.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Report {
+    pub networks: usize,
+    pub settings: Vec<&'static str>,
+    pub assets: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+impl Config {
+    fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.first() == Some(&b'{') {
+            Ok(serde_json::from_slice(data)?)
+        } else {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+    }
+
+    /// The networks and settings that aren't secret, to set up more devices
+    /// like this one. Passwords stay on the device and have to be filled in.
+    pub fn export(networks: &Networks, current: impl Fn(&Def) -> Option<Value>) -> Self {
+        let networks = networks
+            .list()
+            .iter()
+            .map(|n| KnownNetwork {
+                pass: String::new(),
+                ..n.clone()
+            })
+            .collect();
+        let settings = settings::SCHEMA
+            .iter()
+            .filter(|def| !def.secret)
+            .filter_map(|def| Some((def.key.to_string(), current(def)?)))
+            .collect();
+        Self {
+            networks,
+            settings,
+            ..Default::default()
+        }
+    }
+
+    pub fn plan(self, current: &Networks) -> anyhow::Result<Plan> {
+        let networks = if self.networks.is_empty() && !self.replace_networks {
+            None
+        } else {
+            let mut networks = if self.replace_networks {
+                Networks::default()
+            } else {
+                current.clone()
+            };
+            for mut network in self.networks {
+                let ssid = network.ssid.clone();
+                // exports leave passwords out, keep the saved one
+                if network.pass.is_empty() && network.auth != WifiAuth::Open {
+                    match current.list().iter().find(|n| n.ssid == ssid) {
+                        Some(saved) => network.pass = saved.pass.clone(),
+                        None if network.auth == WifiAuth::Auto => anyhow::bail!(
+                            "Network {:?} has no password, use \"auth\":\"open\" for an open one",
+                            ssid
+                        ),
+                        None => {}
+                    }
+                }
+                networks
+                    .add(network)
+                    .map_err(|e| anyhow::anyhow!("Network {:?}: {}", ssid, e))?;
+            }
+            Some(networks)
+        };
+
+        let mut settings = Vec::new();
+        let mut skipped = Vec::new();
+        for (key, value) in self.settings {
+            match settings::def(&key) {
+                Ok(def) => {
+                    let value = def
+                        .validate(value)
+                        .map_err(|e| anyhow::anyhow!("Setting {}: {}", key, e))?;
+                    settings.push((def, value));
+                }
+                Err(_) => {
+                    log::warn!("Skipping unknown setting {} in bundle", key);
+                    skipped.push(key);
+                }
+            }
+        }
+
+        for asset in &self.assets {
+            crate::assets::check(&asset.name, &asset.data)
+                .map_err(|e| anyhow::anyhow!("Asset {:?}: {}", asset.name, e))?;
+        }
+
+        Ok(Plan {
+            networks,
+            settings,
+            assets: self.assets,
+            skipped,
+        })
+    }
+}
+
+impl Bundle {
+    /// An unsigned bundle with `config` as msgpack.
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            version: VERSION,
+            config: rmp_serde::to_vec_named(config)?,
+            signature: None,
+        })
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if let Ok(text) = std::str::from_utf8(data) {
+            let text = text.trim();
+            if text.starts_with('{') {
+                return Ok(serde_json::from_str(text)?);
+            }
+            if let Ok(data) = STANDARD.decode(text) {
+                return Self::parse(&data);
+            }
+        }
+        Ok(rmp_serde::from_slice(data)?)
+    }
+
+    /// The msgpack bundle as base64, for QR codes and the console.
+    pub fn to_text(&self) -> anyhow::Result<String> {
+        Ok(STANDARD.encode(rmp_serde::to_vec_named(self)?))
+    }
+
+    /// Checks the signature against `pubkey`, if there is one, and decodes the
+    /// config.
+    pub fn open(&self, pubkey: Option<&str>) -> anyhow::Result<Config> {
+        if self.version != VERSION {
+            anyhow::bail!("Unsupported bundle version {}", self.version);
+        }
+        match (pubkey, &self.signature) {
+            (Some(pubkey), Some(signature)) => {
+                crate::ota::verify_ed25519(pubkey, &self.config, signature)
+                    .map_err(|e| anyhow::anyhow!("Invalid bundle signature: {}", e))?
+            }
+            (Some(_), None) => anyhow::bail!("The bundle isn't signed"),
+            (None, Some(_)) => log::info!("Bundle signature not checked, no public key"),
+            (None, None) => {}
+        }
+        Config::parse(&self.config)
+    }
+}
+
+#[test]
+fn test_bundle_signature() {
+    let key_pair = ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new([7; 32]));
+    let pubkey: String = key_pair.pk.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let config = Config {
+        settings: BTreeMap::from([("volume".to_string(), Value::Integer(60))]),
+        ..Default::default()
+    };
+    let mut bundle = Bundle::new(&config).unwrap();
+    assert!(bundle.open(Some(&pubkey)).is_err());
+    assert_eq!(bundle.open(None).unwrap(), config);
+
+    bundle.signature = Some(key_pair.sk.sign(&bundle.config, None).to_vec());
+    let text = bundle.to_text().unwrap();
+    let bundle = Bundle::parse(text.as_bytes()).unwrap();
+    assert_eq!(bundle.open(Some(&pubkey)).unwrap(), config);
+
+    // the JSON form carries the same bytes
+    let json = serde_json::to_vec(&bundle).unwrap();
+    let mut bundle = Bundle::parse(&json).unwrap();
+    assert_eq!(bundle.open(Some(&pubkey)).unwrap(), config);
+
+    bundle.config[0] ^= 1;
+    assert!(bundle.open(Some(&pubkey)).is_err());
+}
+
+#[test]
+fn test_bundle_plan() {
+    let json = r#"{"networks":[{"ssid":"class","pass":"12345678"}],
+        "settings":{"server_url":"ws://10.0.0.2:8080/ws","volume":80,"language":"en",
+            "token":"t0ken","voice":"alloy"},
+        "assets":[{"name":"bg.gif","kind":"Background","data":"R0lGODlh","active":true}]}"#;
+    let bundle = Bundle {
+        version: VERSION,
+        config: json.as_bytes().to_vec(),
+        signature: None,
+    };
+    let config = bundle.open(None).unwrap();
+
+    let mut current = Networks::default();
+    current
+        .add(KnownNetwork {
+            ssid: "home".to_string(),
+            pass: "87654321".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+    let plan = config.clone().plan(&current).unwrap();
+    assert_eq!(plan.networks.unwrap().list().len(), 2);
+    let keys: Vec<_> = plan.settings.iter().map(|(def, _)| def.key).collect();
+    assert_eq!(keys, ["language", "server_url", "token", "volume"]);
+    assert_eq!(
+        plan.settings[1].1,
+        Value::Text("ws://10.0.0.2:8080/ws/".to_string())
+    );
+    assert_eq!(plan.skipped, ["voice"]);
+    // only a GIF header, the firmware decodes backgrounds before it imports them
+    assert_eq!(plan.assets[0].data, b"GIF89a");
+
+    let plan = Config {
+        replace_networks: true,
+        ..config.clone()
+    }
+    .plan(&current)
+    .unwrap();
+    assert_eq!(plan.networks.unwrap().list()[0].ssid, "class");
+
+    // an exported network keeps its saved password, a new one needs one
+    let reimport = Config {
+        networks: Config::export(&current, |_| None).networks,
+        ..Default::default()
+    };
+    let plan = reimport.plan(&current).unwrap();
+    assert_eq!(plan.networks.unwrap().list()[0].pass, "87654321");
+    let no_pass = Config {
+        networks: vec![KnownNetwork {
+            ssid: "cafe".to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(no_pass.clone().plan(&current).is_err());
+    let mut open = no_pass;
+    open.networks[0].auth = WifiAuth::Open;
+    assert!(open.plan(&current).is_ok());
+
+    // so does an asset the store would refuse
+    let mut bad = config.clone();
+    bad.assets[0].name = "../bg.gif".to_string();
+    assert!(bad.plan(&current).is_err());
+
+    // one bad value rejects the whole bundle
+    let mut bad = config;
+    bad.settings
+        .insert("volume".to_string(), Value::Integer(200));
+    assert!(bad.plan(&current).is_err());
+
+    let exported = Config::export(&current, |def| match def.key {
+        "volume" => Some(Value::Integer(50)),
+        "token" => Some(Value::Text("t0ken".to_string())),
+        _ => None,
+    });
+    assert_eq!(exported.networks[0].ssid, "home");
+    assert!(exported.networks[0].pass.is_empty());
+    assert_eq!(exported.settings["volume"], Value::Integer(50));
+    // like the passwords, the token stays on the device
+    assert!(!exported.settings.contains_key("token"));
+}
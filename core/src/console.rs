@@ -0,0 +1,309 @@
+use std::str::FromStr;
+
+/// Longest line kept, the rest of a longer line is dropped. Long enough for
+/// a configuration bundle without assets.
+const MAX_LINE_LEN: usize = 4096;
+
+/// A line typed on the serial console.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command<'a> {
+    Help,
+    Get {
+        key: &'a str,
+    },
+    Set {
+        key: &'a str,
+        value: &'a str,
+    },
+    WifiScan,
+    Status,
+    Heap,
+    Reboot,
+    FactoryReset,
+    /// A configuration bundle as text, see `bundle::Bundle::parse`.
+    Import {
+        bundle: &'a str,
+    },
+    Export,
+    Play {
+        name: &'a str,
+    },
+    /// Prints the current level without one.
+    LogLevel(Option<log::LevelFilter>),
+}
+
+struct Entry {
+    name: &'static str,
+    args: &'static str,
+    help: &'static str,
+    /// Gets the rest of the line after the name, trimmed.
+    parse: for<'a> fn(&'a str) -> anyhow::Result<Command<'a>>,
+}
+
+const COMMANDS: &[Entry] = &[
+    Entry {
+        name: "help",
+        args: "",
+        help: "list the commands",
+        parse: |_| Ok(Command::Help),
+    },
+    Entry {
+        name: "get",
+        args: "<key>",
+        help: "print a setting",
+        parse: |args| {
+            Ok(Command::Get {
+                key: one_arg(args)?,
+            })
+        },
+    },
+    Entry {
+        name: "set",
+        args: "<key> <value>",
+        help: "change a setting",
+        parse: |args| {
+            let (key, value) = args
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow::anyhow!("Missing value"))?;
+            Ok(Command::Set {
+                key,
+                value: value.trim(),
+            })
+        },
+    },
+    Entry {
+        name: "wifi scan",
+        args: "",
+        help: "list the access points in range",
+        parse: |_| Ok(Command::WifiScan),
+    },
+    Entry {
+        name: "status",
+        args: "",
+        help: "print the device status",
+        parse: |_| Ok(Command::Status),
+    },
+    Entry {
+        name: "heap",
+        args: "",
+        help: "print the free heap",
+        parse: |_| Ok(Command::Heap),
+    },
+    Entry {
+        name: "reboot",
+        args: "",
+        help: "restart the device",
+        parse: |_| Ok(Command::Reboot),
+    },
+    Entry {
+        name: "factory-reset",
+        args: "confirm",
+        help: "erase all settings and assets, restart into setup",
+        // a stray line on the serial port shouldn't erase the device
+        parse: |args| match args {
+            "confirm" => Ok(Command::FactoryReset),
+            _ => anyhow::bail!("This erases everything, type factory-reset confirm to go ahead"),
+        },
+    },
+    Entry {
+        name: "import",
+        args: "<bundle>",
+        help: "import a configuration bundle",
+        parse: |args| {
+            if args.is_empty() {
+                anyhow::bail!("Missing argument");
+            }
+            Ok(Command::Import { bundle: args })
+        },
+    },
+    Entry {
+        name: "export",
+        args: "",
+        help: "print the networks and settings as a bundle, without passwords",
+        parse: |_| Ok(Command::Export),
+    },
+    Entry {
+        name: "play",
+        args: "<asset>",
+        help: "play a stored sound",
+        parse: |args| {
+            Ok(Command::Play {
+                name: one_arg(args)?,
+            })
+        },
+    },
+    Entry {
+        name: "log level",
+        args: "[off|error|warn|info|debug|trace]",
+        help: "print or change the log level",
+        parse: |args| {
+            if args.is_empty() {
+                return Ok(Command::LogLevel(None));
+            }
+            let level = log::LevelFilter::from_str(args)
+                .map_err(|_| anyhow::anyhow!("Unknown log level {}", args))?;
+            Ok(Command::LogLevel(Some(level)))
+        },
+    },
+];
+
+fn one_arg(args: &str) -> anyhow::Result<&str> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [arg] => Ok(arg),
+        [] => anyhow::bail!("Missing argument"),
+        _ => anyhow::bail!("Too many arguments"),
+    }
+}
+
+impl<'a> Command<'a> {
+    /// `None` for an empty line.
+    pub fn parse(line: &'a str) -> anyhow::Result<Option<Self>> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        for entry in COMMANDS {
+            let mut rest = line;
+            let matched = entry.name.split(' ').all(|word| {
+                let mut parts = rest.splitn(2, char::is_whitespace);
+                let found = parts.next() == Some(word);
+                rest = parts.next().unwrap_or("").trim_start();
+                found
+            });
+            if matched {
+                return (entry.parse)(rest.trim()).map(Some);
+            }
+        }
+        anyhow::bail!("Unknown command, type help for a list")
+    }
+}
+
+pub fn help() -> String {
+    COMMANDS
+        .iter()
+        .map(|e| format!("{:<40} {}\n", format!("{} {}", e.name, e.args), e.help))
+        .collect()
+}
+
+/// Collects typed bytes into lines, handling backspace and CR/LF.
+#[derive(Default)]
+pub struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Returns the line when `byte` ends one that isn't empty.
+    pub fn push(&mut self, byte: u8) -> Option<String> {
+        match byte {
+            b'\r' | b'\n' => {
+                if self.buf.is_empty() {
+                    return None;
+                }
+                let line = String::from_utf8_lossy(&self.buf).into_owned();
+                self.buf.clear();
+                return Some(line);
+            }
+            0x08 | 0x7f => {
+                self.buf.pop();
+            }
+            _ if self.buf.len() < MAX_LINE_LEN => self.buf.push(byte),
+            _ => {}
+        }
+        None
+    }
+}
+
+#[test]
+fn test_console_parse() {
+    assert_eq!(Command::parse("  ").unwrap(), None);
+    assert_eq!(
+        Command::parse("get server_url").unwrap(),
+        Some(Command::Get { key: "server_url" })
+    );
+    assert_eq!(
+        Command::parse("set  server_url   ws://echokit.dev/ws/ ").unwrap(),
+        Some(Command::Set {
+            key: "server_url",
+            value: "ws://echokit.dev/ws/"
+        })
+    );
+    assert_eq!(
+        Command::parse("wifi   scan").unwrap(),
+        Some(Command::WifiScan)
+    );
+    assert_eq!(
+        Command::parse("log level DEBUG").unwrap(),
+        Some(Command::LogLevel(Some(log::LevelFilter::Debug)))
+    );
+    assert_eq!(
+        Command::parse("log level").unwrap(),
+        Some(Command::LogLevel(None))
+    );
+    assert_eq!(
+        Command::parse("play chime").unwrap(),
+        Some(Command::Play { name: "chime" })
+    );
+    assert_eq!(
+        Command::parse(r#"import {"version": 1, "config": "e30="}"#).unwrap(),
+        Some(Command::Import {
+            bundle: r#"{"version": 1, "config": "e30="}"#
+        })
+    );
+    assert_eq!(
+        Command::parse("factory-reset  confirm").unwrap(),
+        Some(Command::FactoryReset)
+    );
+
+    let invalid = [
+        "wifi",
+        "wifi connect",
+        "get",
+        "get a b",
+        "set volume",
+        "import",
+        "log level loud",
+        "reboots",
+        "factory-reset",
+        "factory-reset now",
+    ];
+    for line in invalid {
+        assert!(Command::parse(line).is_err(), "{}", line);
+    }
+
+    // every command is listed
+    let help = help();
+    assert_eq!(help.lines().count(), COMMANDS.len());
+    assert!(help.contains("set <key> <value>"));
+
+    let volume = crate::settings::def("volume").unwrap();
+    assert_eq!(
+        volume.parse_str("60").unwrap(),
+        crate::settings::Value::Integer(60)
+    );
+    assert!(volume.parse_str("loud").is_err());
+    assert!(volume.parse_str("101").is_err());
+    let ble_remote = crate::settings::def("ble_remote").unwrap();
+    assert_eq!(
+        ble_remote.parse_str("on").unwrap(),
+        crate::settings::Value::Bool(true)
+    );
+}
+
+#[test]
+fn test_console_lines() {
+    let mut lines = LineBuffer::default();
+    let mut push = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .filter_map(|b| lines.push(*b))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(push(b"heap\r\n"), vec!["heap"]);
+    assert_eq!(push(b"\r\n\n"), Vec::<String>::new());
+    assert_eq!(push(b"statsu\x08\x08us\r"), vec!["status"]);
+    assert_eq!(push(b"\x7f\x7fa\n"), vec!["a"]);
+
+    let long = push(&[b'x'; MAX_LINE_LEN + 10]);
+    assert!(long.is_empty());
+    assert_eq!(push(b"\n")[0].len(), MAX_LINE_LEN);
+}
@@ -0,0 +1,253 @@
+pub const SERIAL_HEADER: &[u8; 6] = b"IMPROV";
+const SERIAL_VERSION: u8 = 1;
+
+/// Serial packet types.
+pub const SERIAL_CURRENT_STATE: u8 = 0x01;
+pub const SERIAL_ERROR_STATE: u8 = 0x02;
+pub const SERIAL_RPC: u8 = 0x03;
+pub const SERIAL_RPC_RESULT: u8 = 0x04;
+
+const CMD_WIFI_SETTINGS: u8 = 0x01;
+const CMD_IDENTIFY: u8 = 0x02;
+const CMD_DEVICE_INFO: u8 = 0x03;
+const CMD_SCAN: u8 = 0x04;
+/// Vendor extension, not part of the standard.
+const CMD_SERVER_URL: u8 = 0x80;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    AuthorizationRequired = 0x01,
+    Authorized = 0x02,
+    Provisioning = 0x03,
+    Provisioned = 0x04,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    None = 0x00,
+    InvalidRpc = 0x01,
+    UnknownRpc = 0x02,
+    UnableToConnect = 0x03,
+    NotAuthorized = 0x04,
+    Unknown = 0xff,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rpc {
+    WifiSettings {
+        ssid: String,
+        pass: String,
+    },
+    /// Over serial this command asks for the current state instead.
+    Identify,
+    DeviceInfo,
+    Scan,
+    /// Reads the server URL, or sets it when one is given.
+    ServerUrl(Option<String>),
+}
+
+impl Rpc {
+    pub fn parse(command: u8, data: &[u8]) -> Result<Self, ErrorCode> {
+        match command {
+            CMD_WIFI_SETTINGS => match &strings(data).ok_or(ErrorCode::InvalidRpc)?[..] {
+                [ssid, pass] => Ok(Rpc::WifiSettings {
+                    ssid: ssid.clone(),
+                    pass: pass.clone(),
+                }),
+                _ => Err(ErrorCode::InvalidRpc),
+            },
+            CMD_IDENTIFY => Ok(Rpc::Identify),
+            CMD_DEVICE_INFO => Ok(Rpc::DeviceInfo),
+            CMD_SCAN => Ok(Rpc::Scan),
+            CMD_SERVER_URL => match &strings(data).ok_or(ErrorCode::InvalidRpc)?[..] {
+                [] => Ok(Rpc::ServerUrl(None)),
+                [url] => Ok(Rpc::ServerUrl(Some(url.clone()))),
+                _ => Err(ErrorCode::InvalidRpc),
+            },
+            _ => Err(ErrorCode::UnknownRpc),
+        }
+    }
+
+    /// Parses the value written to the BLE RPC command characteristic,
+    /// `[command][len][data][checksum]`.
+    pub fn parse_ble(packet: &[u8]) -> Result<Self, ErrorCode> {
+        let [command, len, rest @ ..] = packet else {
+            return Err(ErrorCode::InvalidRpc);
+        };
+        if rest.len() != *len as usize + 1
+            || checksum(&packet[..packet.len() - 1]) != packet[packet.len() - 1]
+        {
+            return Err(ErrorCode::InvalidRpc);
+        }
+        Self::parse(*command, &rest[..*len as usize])
+    }
+
+    pub fn command(&self) -> u8 {
+        match self {
+            Rpc::WifiSettings { .. } => CMD_WIFI_SETTINGS,
+            Rpc::Identify => CMD_IDENTIFY,
+            Rpc::DeviceInfo => CMD_DEVICE_INFO,
+            Rpc::Scan => CMD_SCAN,
+            Rpc::ServerUrl(_) => CMD_SERVER_URL,
+        }
+    }
+}
+
+/// Length-prefixed strings, as in RPC data and results.
+fn strings(mut data: &[u8]) -> Option<Vec<String>> {
+    let mut strings = Vec::new();
+    while let [len, rest @ ..] = data {
+        let s = rest.get(..*len as usize)?;
+        strings.push(String::from_utf8(s.to_vec()).ok()?);
+        data = &rest[*len as usize..];
+    }
+    Some(strings)
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// `[command][len][strings]`, the serial RPC result. BLE adds a checksum.
+pub fn rpc_result(command: u8, strings: &[String]) -> Vec<u8> {
+    let mut data = vec![command, 0];
+    for s in strings {
+        let s = &s.as_bytes()[..s.len().min(255)];
+        data.push(s.len() as u8);
+        data.extend_from_slice(s);
+    }
+    data[1] = (data.len() - 2).min(255) as u8;
+    data
+}
+
+pub fn with_checksum(mut data: Vec<u8>) -> Vec<u8> {
+    data.push(checksum(&data));
+    data
+}
+
+pub fn serial_frame(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = SERIAL_HEADER.to_vec();
+    frame.extend_from_slice(&[SERIAL_VERSION, kind, data.len() as u8]);
+    frame.extend_from_slice(data);
+    with_checksum(frame)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// Bytes that turned out not to be a packet, for the console.
+    Text(Vec<u8>),
+    Packet {
+        kind: u8,
+        data: Vec<u8>,
+    },
+    /// A packet with a bad version or checksum.
+    Invalid,
+}
+
+/// Picks Improv packets out of the serial input, which is shared with the
+/// console.
+#[derive(Default)]
+pub struct SerialDecoder {
+    buf: Vec<u8>,
+}
+
+impl SerialDecoder {
+    pub fn push(&mut self, byte: u8) -> Option<Input> {
+        self.buf.push(byte);
+        let n = self.buf.len();
+        if n <= SERIAL_HEADER.len() {
+            if self.buf[..] != SERIAL_HEADER[..n] {
+                return Some(Input::Text(std::mem::take(&mut self.buf)));
+            }
+            return None;
+        }
+        // version, type and length follow the header
+        if n < 9 || n < 10 + self.buf[8] as usize {
+            return None;
+        }
+        let packet = std::mem::take(&mut self.buf);
+        if packet[6] != SERIAL_VERSION || checksum(&packet[..n - 1]) != packet[n - 1] {
+            return Some(Input::Invalid);
+        }
+        Some(Input::Packet {
+            kind: packet[7],
+            data: packet[9..n - 1].to_vec(),
+        })
+    }
+}
+
+#[test]
+fn test_improv_rpc() {
+    let mut packet = vec![CMD_WIFI_SETTINGS, 12, 4];
+    packet.extend_from_slice(b"home");
+    packet.push(6);
+    packet.extend_from_slice(b"secret");
+    let packet = with_checksum(packet);
+    assert_eq!(
+        Rpc::parse_ble(&packet).unwrap(),
+        Rpc::WifiSettings {
+            ssid: "home".to_string(),
+            pass: "secret".to_string()
+        }
+    );
+
+    let mut bad = packet.clone();
+    *bad.last_mut().unwrap() ^= 1;
+    assert_eq!(Rpc::parse_ble(&bad), Err(ErrorCode::InvalidRpc));
+    assert_eq!(Rpc::parse_ble(&packet[..5]), Err(ErrorCode::InvalidRpc));
+    assert_eq!(
+        Rpc::parse_ble(&with_checksum(vec![0x42, 0])),
+        Err(ErrorCode::UnknownRpc)
+    );
+    // a string running past the data
+    assert_eq!(
+        Rpc::parse(CMD_WIFI_SETTINGS, &[4, b'h', b'o']),
+        Err(ErrorCode::InvalidRpc)
+    );
+
+    assert_eq!(Rpc::parse(CMD_SERVER_URL, &[]), Ok(Rpc::ServerUrl(None)));
+    let mut data = vec![9];
+    data.extend_from_slice(b"ws://a/ws");
+    assert_eq!(
+        Rpc::parse(CMD_SERVER_URL, &data),
+        Ok(Rpc::ServerUrl(Some("ws://a/ws".to_string())))
+    );
+
+    let result = rpc_result(CMD_DEVICE_INFO, &["EchoKit".to_string(), "0.1".to_string()]);
+    assert_eq!(result, b"\x03\x0c\x07EchoKit\x030.1");
+    assert_eq!(rpc_result(CMD_SCAN, &[]), vec![CMD_SCAN, 0]);
+}
+
+#[test]
+fn test_improv_serial() {
+    let frame = serial_frame(SERIAL_RPC, &[CMD_IDENTIFY, 0]);
+    assert_eq!(&frame[..9], b"IMPROV\x01\x03\x02");
+    assert_eq!(*frame.last().unwrap(), checksum(&frame[..frame.len() - 1]));
+
+    let mut decoder = SerialDecoder::default();
+    let mut inputs = Vec::new();
+    for b in b"hea".iter().chain(&frame).chain(b"IMP\n") {
+        inputs.extend(decoder.push(*b));
+    }
+    assert_eq!(
+        inputs,
+        vec![
+            Input::Text(b"h".to_vec()),
+            Input::Text(b"e".to_vec()),
+            Input::Text(b"a".to_vec()),
+            Input::Packet {
+                kind: SERIAL_RPC,
+                data: vec![CMD_IDENTIFY, 0]
+            },
+            Input::Text(b"IMP\n".to_vec()),
+        ]
+    );
+
+    let mut bad = frame.clone();
+    *bad.last_mut().unwrap() ^= 1;
+    let inputs: Vec<_> = bad.iter().filter_map(|b| decoder.push(*b)).collect();
+    assert_eq!(inputs, vec![Input::Invalid]);
+}
@@ -0,0 +1,15 @@
+//! The parts of the EchoKit firmware that don't touch ESP-IDF. The firmware
+//! crate re-exports these modules and adds the device side next to them.
+
+pub mod assets;
+pub mod bundle;
+pub mod console;
+pub mod improv;
+pub mod networks;
+pub mod ota;
+pub mod portal;
+pub mod protocol;
+pub mod remote;
+pub mod secrets;
+pub mod settings;
+pub mod transfer;
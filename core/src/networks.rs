@@ -0,0 +1,524 @@
+use std::net::Ipv4Addr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Storage;
+
+pub const NVS_KEY: &str = "networks";
+const IP_NVS_KEY: &str = "ip_config";
+pub const MAX_NETWORKS: usize = 8;
+/// A PEM certificate or two, the networks are stored in a single NVS blob.
+pub const MAX_CA_CERT_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiAuth {
+    /// Open without a password, WPA2/WPA3 personal with one.
+    #[default]
+    Auto,
+    Open,
+    Wpa2Personal,
+    Wpa3Personal,
+    /// PEAP/MSCHAPv2 with `username` and `pass`, the server checked against
+    /// `ca_cert`.
+    Wpa2Enterprise,
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    #[serde(default)]
+    pub pass: String,
+    /// Higher goes first when no scan result tells networks apart.
+    #[serde(default)]
+    pub priority: u8,
+    #[serde(default)]
+    pub auth: WifiAuth,
+    /// EAP user name, enterprise only.
+    #[serde(default)]
+    pub username: String,
+    /// Outer EAP identity, the user name when empty. Enterprise only.
+    #[serde(default)]
+    pub identity: String,
+    /// The SSID isn't broadcast, so it never shows up in scans.
+    #[serde(default)]
+    pub hidden: bool,
+    /// PEM CA certificate of the authentication server, enterprise only.
+    /// Without one the server isn't verified, and anyone posing as the
+    /// network gets the password.
+    #[serde(default)]
+    pub ca_cert: String,
+}
+
+// keeps the password out of logs
+impl std::fmt::Debug for KnownNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KnownNetwork")
+            .field("ssid", &self.ssid)
+            .field("priority", &self.priority)
+            .field("auth", &self.auth)
+            .field("username", &self.username)
+            .field("hidden", &self.hidden)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Wi-Fi networks the device may join, stored as JSON in NVS.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Networks(Vec<KnownNetwork>);
+
+/// Writes to the networks characteristic.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum NetworkCommand {
+    Add(KnownNetwork),
+    Remove { ssid: String },
+}
+
+#[derive(Serialize)]
+struct NetworkSummary<'a> {
+    ssid: &'a str,
+    priority: u8,
+    auth: WifiAuth,
+    hidden: bool,
+}
+
+impl Networks {
+    pub fn list(&self) -> &[KnownNetwork] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Adds a network, replacing a saved one with the same SSID.
+    pub fn add(&mut self, network: KnownNetwork) -> anyhow::Result<()> {
+        if network.ssid.is_empty() || network.ssid.len() > 32 {
+            anyhow::bail!("Invalid SSID: {:?}", network.ssid);
+        }
+        if network.pass.len() > 64 {
+            anyhow::bail!("Password is too long");
+        }
+        match network.auth {
+            WifiAuth::Open if !network.pass.is_empty() => {
+                anyhow::bail!("Open networks have no password")
+            }
+            WifiAuth::Wpa2Personal | WifiAuth::Wpa3Personal => check_psk(&network.pass)?,
+            WifiAuth::Auto if !network.pass.is_empty() => check_psk(&network.pass)?,
+            WifiAuth::Wpa2Enterprise if network.username.is_empty() || network.pass.is_empty() => {
+                anyhow::bail!("Enterprise networks need a user name and password")
+            }
+            _ => {}
+        }
+        if !network.ca_cert.is_empty() {
+            if network.auth != WifiAuth::Wpa2Enterprise {
+                anyhow::bail!("Only enterprise networks take a CA certificate");
+            }
+            if network.ca_cert.len() > MAX_CA_CERT_LEN
+                || !network
+                    .ca_cert
+                    .trim_start()
+                    .starts_with("-----BEGIN CERTIFICATE-----")
+            {
+                anyhow::bail!(
+                    "The CA certificate must be PEM, up to {} bytes",
+                    MAX_CA_CERT_LEN
+                );
+            }
+        }
+        if let Some(n) = self.0.iter_mut().find(|n| n.ssid == network.ssid) {
+            *n = network;
+        } else if self.0.len() >= MAX_NETWORKS {
+            anyhow::bail!("At most {} networks can be saved", MAX_NETWORKS);
+        } else {
+            self.0.push(network);
+        }
+        self.0.sort_by_key(|n| std::cmp::Reverse(n.priority));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, ssid: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|n| n.ssid != ssid);
+        self.0.len() != len
+    }
+
+    pub fn apply(&mut self, cmd: NetworkCommand) -> anyhow::Result<()> {
+        match cmd {
+            NetworkCommand::Add(network) => self.add(network),
+            NetworkCommand::Remove { ssid } => {
+                if !self.remove(&ssid) {
+                    anyhow::bail!("Unknown network {:?}", ssid);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The saved networks without their passwords, for reading over BLE.
+    pub fn summary_json(&self) -> String {
+        let summary: Vec<_> = self
+            .0
+            .iter()
+            .map(|n| NetworkSummary {
+                ssid: &n.ssid,
+                priority: n.priority,
+                auth: n.auth,
+                hidden: n.hidden,
+            })
+            .collect();
+        serde_json::to_string(&summary).unwrap_or_default()
+    }
+
+    /// Order to try networks in: the strongest known SSID in `visible`
+    /// (ssid, rssi) first, then hidden networks, then the rest of the list
+    /// (ones the scan missed), each by priority.
+    pub fn candidates(&self, visible: &[(&str, i8)]) -> Vec<&KnownNetwork> {
+        let rssi = |n: &KnownNetwork| {
+            let rssi = visible
+                .iter()
+                .filter(|(ssid, _)| *ssid == n.ssid)
+                .map(|(_, rssi)| *rssi)
+                .max();
+            (rssi, n.hidden)
+        };
+        let mut candidates: Vec<_> = self.0.iter().map(|n| (rssi(n), n)).collect();
+        // stable, so equal entries keep their priority order
+        candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+        candidates.into_iter().map(|(_, n)| n).collect()
+    }
+}
+
+/// WPA passphrases have 8 to 63 characters, 64 are a raw hex key.
+fn check_psk(pass: &str) -> anyhow::Result<()> {
+    match pass.len() {
+        8..=63 => Ok(()),
+        64 if pass.chars().all(|c| c.is_ascii_hexdigit()) => Ok(()),
+        64 => anyhow::bail!("A 64 character WPA password must be a hex key"),
+        _ => anyhow::bail!("WPA passwords have 8 to 63 characters"),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl StaticIp {
+    pub fn prefix_len(&self) -> u8 {
+        u32::from(self.netmask).leading_ones() as u8
+    }
+}
+
+/// How the station gets its address, shared by all networks.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IpSettings {
+    /// DHCP when unset.
+    #[serde(default)]
+    pub static_ip: Option<StaticIp>,
+    /// Replaces the DNS server from DHCP, required with a static IP.
+    #[serde(default)]
+    pub dns: Option<Ipv4Addr>,
+    /// `echokit-<mac>` when unset.
+    #[serde(default)]
+    pub hostname: Option<String>,
+}
+
+impl IpSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(s) = &self.static_ip {
+            let mask = u32::from(s.netmask);
+            if mask.leading_ones() + mask.trailing_zeros() != 32 || mask == 0 {
+                anyhow::bail!("Invalid netmask {}", s.netmask);
+            }
+            if u32::from(s.ip) & mask != u32::from(s.gateway) & mask {
+                anyhow::bail!(
+                    "Gateway {} is not in {}/{}",
+                    s.gateway,
+                    s.ip,
+                    s.prefix_len()
+                );
+            }
+            if self.dns.is_none() {
+                anyhow::bail!("A static IP needs a DNS server");
+            }
+        }
+        if let Some(hostname) = &self.hostname {
+            // the netif keeps at most 30 bytes
+            if hostname.is_empty()
+                || hostname.len() > 30
+                || hostname.starts_with('-')
+                || !hostname
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                anyhow::bail!("Invalid hostname: {:?}", hostname);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An access point found by a scan. `auth` uses the codes of [`encode_scan`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanEntry {
+    pub ssid: String,
+    pub rssi: i8,
+    pub auth: u8,
+}
+
+/// Largest value a BLE characteristic can hold.
+const MAX_SCAN_LEN: usize = 512;
+
+/// Packs scan results for the scan characteristic, strongest first with one
+/// entry per SSID, as `[rssi: i8][auth: u8][len: u8][ssid: len bytes]`
+/// repeated. Auth codes: 0 open, 1 WEP, 2 WPA, 3 WPA2, 4 WPA/WPA2,
+/// 5 WPA2-Enterprise, 6 WPA3, 7 WPA2/WPA3, 8 WAPI, 255 other.
+///
+/// Hidden APs are left out, and weak ones dropped if the list doesn't fit.
+pub fn encode_scan(mut entries: Vec<ScanEntry>) -> Vec<u8> {
+    entries.retain(|e| !e.ssid.is_empty() && e.ssid.len() <= 32);
+    entries.sort_by_key(|e| std::cmp::Reverse(e.rssi));
+
+    let mut seen = std::collections::HashSet::new();
+    let mut data = Vec::with_capacity(MAX_SCAN_LEN);
+    for e in entries {
+        if !seen.insert(e.ssid.clone()) {
+            continue;
+        }
+        if data.len() + 3 + e.ssid.len() > MAX_SCAN_LEN {
+            break;
+        }
+        data.push(e.rssi as u8);
+        data.push(e.auth);
+        data.push(e.ssid.len() as u8);
+        data.extend_from_slice(e.ssid.as_bytes());
+    }
+    data
+}
+
+/// Loads the saved networks, none if they can't be read.
+pub fn load(storage: &dyn Storage) -> Networks {
+    match storage.get_blob(NVS_KEY) {
+        Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            log::error!("Invalid saved networks: {:?}", e);
+            Networks::default()
+        }),
+        Ok(None) => Networks::default(),
+        Err(e) => {
+            log::error!("Failed to read saved networks: {:?}", e);
+            Networks::default()
+        }
+    }
+}
+
+pub fn save(storage: &mut dyn Storage, networks: &Networks) -> anyhow::Result<()> {
+    storage.set_blob(NVS_KEY, &serde_json::to_vec(networks)?)
+}
+
+pub fn load_ip(storage: &dyn Storage) -> IpSettings {
+    match storage.get_blob(IP_NVS_KEY) {
+        Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            log::error!("Invalid IP settings: {:?}", e);
+            IpSettings::default()
+        }),
+        Ok(None) => IpSettings::default(),
+        Err(e) => {
+            log::error!("Failed to read IP settings: {:?}", e);
+            IpSettings::default()
+        }
+    }
+}
+
+pub fn save_ip(storage: &mut dyn Storage, ip: &IpSettings) -> anyhow::Result<()> {
+    ip.validate()?;
+    storage.set_blob(IP_NVS_KEY, &serde_json::to_vec(ip)?)
+}
+
+#[test]
+fn test_networks() {
+    let net = |ssid: &str, priority| KnownNetwork {
+        ssid: ssid.to_string(),
+        pass: "secret-pass".to_string(),
+        priority,
+        ..Default::default()
+    };
+
+    let mut networks = Networks::default();
+    networks.add(net("home", 1)).unwrap();
+    networks.add(net("office", 2)).unwrap();
+    networks
+        .add(KnownNetwork {
+            hidden: true,
+            ..net("hidden", 0)
+        })
+        .unwrap();
+    networks.add(net("home", 3)).unwrap();
+    assert_eq!(networks.list().len(), 3);
+    assert!(networks.add(net("", 0)).is_err());
+    let enterprise = KnownNetwork {
+        auth: WifiAuth::Wpa2Enterprise,
+        ..net("campus", 0)
+    };
+    assert!(networks.add(enterprise.clone()).is_err());
+    let enterprise = KnownNetwork {
+        username: "student".to_string(),
+        ..enterprise
+    };
+    networks.add(enterprise.clone()).unwrap();
+    let ca_cert = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+    networks
+        .add(KnownNetwork {
+            ca_cert: ca_cert.to_string(),
+            ..enterprise.clone()
+        })
+        .unwrap();
+    assert!(!format!("{:?}", networks.list()).contains("BEGIN"));
+    assert!(networks
+        .add(KnownNetwork {
+            ca_cert: "not a certificate".to_string(),
+            ..enterprise
+        })
+        .is_err());
+    assert!(networks
+        .add(KnownNetwork {
+            ca_cert: ca_cert.to_string(),
+            ..net("home", 1)
+        })
+        .is_err());
+    networks.remove("campus");
+
+    // nothing visible: hidden first, then priority order
+    let order: Vec<_> = networks
+        .candidates(&[])
+        .iter()
+        .map(|n| n.ssid.as_str())
+        .collect();
+    assert_eq!(order, ["hidden", "home", "office"]);
+
+    // the strongest visible one wins over priority
+    let order: Vec<_> = networks
+        .candidates(&[("home", -80), ("cafe", -30), ("office", -50)])
+        .iter()
+        .map(|n| n.ssid.as_str())
+        .collect();
+    assert_eq!(order, ["office", "home", "hidden"]);
+
+    let cmd: NetworkCommand = serde_json::from_str(r#"{"op":"remove","ssid":"home"}"#).unwrap();
+    networks.apply(cmd).unwrap();
+    let cmd: NetworkCommand =
+        serde_json::from_str(r#"{"op":"add","ssid":"lab","pass":"pw"}"#).unwrap();
+    assert!(networks.apply(cmd).is_err());
+    let cmd: NetworkCommand =
+        serde_json::from_str(r#"{"op":"add","ssid":"lab","pass":"password"}"#).unwrap();
+    networks.apply(cmd).unwrap();
+    // open, or WPA with a password of 8 to 63 characters
+    networks.add(net("cafe", 0)).unwrap();
+    networks
+        .add(KnownNetwork {
+            pass: String::new(),
+            ..net("cafe", 0)
+        })
+        .unwrap();
+    networks
+        .add(KnownNetwork {
+            pass: "0123456789abcdef".repeat(4),
+            ..net("cafe", 0)
+        })
+        .unwrap();
+    assert!(networks
+        .add(KnownNetwork {
+            pass: "x".repeat(64),
+            ..net("cafe", 0)
+        })
+        .is_err());
+    networks.remove("cafe");
+    assert_eq!(
+        networks.summary_json(),
+        concat!(
+            r#"[{"ssid":"office","priority":2,"auth":"auto","hidden":false},"#,
+            r#"{"ssid":"hidden","priority":0,"auth":"auto","hidden":true},"#,
+            r#"{"ssid":"lab","priority":0,"auth":"auto","hidden":false}]"#
+        )
+    );
+}
+
+#[test]
+fn test_ip_settings() {
+    let ip: IpSettings = serde_json::from_str(
+        r#"{"static_ip":{"ip":"192.168.1.50","gateway":"192.168.1.1","netmask":"255.255.255.0"},
+            "dns":"192.168.1.2","hostname":"echokit-lab"}"#,
+    )
+    .unwrap();
+    ip.validate().unwrap();
+    assert_eq!(ip.static_ip.as_ref().unwrap().prefix_len(), 24);
+
+    let bad_mask = IpSettings {
+        static_ip: Some(StaticIp {
+            netmask: Ipv4Addr::new(255, 0, 255, 0),
+            ..ip.static_ip.clone().unwrap()
+        }),
+        ..ip.clone()
+    };
+    assert!(bad_mask.validate().is_err());
+
+    let other_subnet = IpSettings {
+        static_ip: Some(StaticIp {
+            gateway: Ipv4Addr::new(10, 0, 0, 1),
+            ..ip.static_ip.clone().unwrap()
+        }),
+        ..ip.clone()
+    };
+    assert!(other_subnet.validate().is_err());
+
+    let no_dns = IpSettings {
+        dns: None,
+        ..ip.clone()
+    };
+    assert!(no_dns.validate().is_err());
+
+    let bad_hostname = IpSettings {
+        hostname: Some("echo kit".to_string()),
+        ..ip
+    };
+    assert!(bad_hostname.validate().is_err());
+
+    // DHCP with nothing else set
+    IpSettings::default().validate().unwrap();
+}
+
+#[test]
+fn test_encode_scan() {
+    let entry = |ssid: &str, rssi, auth| ScanEntry {
+        ssid: ssid.to_string(),
+        rssi,
+        auth,
+    };
+    let data = encode_scan(vec![
+        entry("home", -70, 3),
+        entry("", -20, 0),
+        entry("cafe", -40, 0),
+        entry("home", -50, 3),
+    ]);
+    assert_eq!(
+        data,
+        [
+            &[-40i8 as u8, 0, 4][..],
+            b"cafe",
+            &[-50i8 as u8, 3, 4],
+            b"home"
+        ]
+        .concat()
+    );
+
+    let many: Vec<_> = (0..40)
+        .map(|i| entry(&format!("network-{:02}", i), -(i as i8), 3))
+        .collect();
+    let data = encode_scan(many);
+    assert!(data.len() <= MAX_SCAN_LEN);
+    assert_eq!(&data[3..13], b"network-00");
+}
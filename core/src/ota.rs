@@ -0,0 +1,344 @@
+use sha2::Digest;
+
+use crate::protocol::FirmwareImage;
+
+/// Build with `ECHOKIT_OTA_PUBKEY=<hex ed25519 public key>` to only accept
+/// images signed with the matching private key.
+const OTA_PUBKEY: Option<&str> = option_env!("ECHOKIT_OTA_PUBKEY");
+
+/// Whether `offered` is a later `major.minor.patch` version than `current`.
+pub fn is_newer(current: &str, offered: &str) -> bool {
+    fn parse(v: &str) -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['.', '-', '+'])
+            .map_while(|p| p.parse().ok())
+            .collect()
+    }
+    parse(offered) > parse(current)
+}
+
+/// Destination of an update, the inactive OTA slot on the device.
+pub trait FlashWriter {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    /// Finalizes the image and makes it the next boot target.
+    fn finish(&mut self) -> anyhow::Result<()>;
+    fn abort(&mut self);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OtaState {
+    Downloading { written: usize, total: usize },
+    Verifying,
+    Ready,
+    Failed(String),
+}
+
+/// What decides whether an update is taken now, besides the image.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Readiness {
+    /// Listening, recording or speaking.
+    pub busy: bool,
+    /// Another firmware or asset transfer is running.
+    pub transferring: bool,
+    /// The last reset was a brownout. Neither board can read its battery
+    /// level, this is the sign that the supply may not last an update.
+    pub brownout: bool,
+}
+
+impl Readiness {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.brownout {
+            anyhow::bail!("Power supply is unstable, the last reset was a brownout");
+        }
+        if self.busy {
+            anyhow::bail!("Device is busy");
+        }
+        if self.transferring {
+            anyhow::bail!("Another transfer is in progress");
+        }
+        Ok(())
+    }
+}
+
+/// Writes an image to flash while hashing it, and only marks it bootable
+/// once size, hash and signature check out.
+pub struct Updater<W: FlashWriter> {
+    image: FirmwareImage,
+    writer: W,
+    hasher: sha2::Sha256,
+    written: usize,
+    state: OtaState,
+}
+
+impl<W: FlashWriter> Updater<W> {
+    fn check(image: &FirmwareImage) -> anyhow::Result<()> {
+        if image.size == 0 {
+            anyhow::bail!("Empty firmware image");
+        }
+        if image.sha256.len() != 32 {
+            anyhow::bail!("Invalid firmware hash");
+        }
+        if OTA_PUBKEY.is_some() && image.signature.is_none() {
+            anyhow::bail!("Firmware image is not signed");
+        }
+        Ok(())
+    }
+
+    /// Rejects images that can't be verified, then calls `open` with the
+    /// image size for the writer, so no slot is opened for a bad image.
+    pub fn new(
+        image: FirmwareImage,
+        open: impl FnOnce(usize) -> anyhow::Result<W>,
+    ) -> anyhow::Result<Self> {
+        Self::check(&image)?;
+        let writer = open(image.size as usize)?;
+        let total = image.size as usize;
+        Ok(Self {
+            image,
+            writer,
+            hasher: sha2::Sha256::new(),
+            written: 0,
+            state: OtaState::Downloading { written: 0, total },
+        })
+    }
+
+    pub fn state(&self) -> &OtaState {
+        &self.state
+    }
+
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn version(&self) -> &str {
+        &self.image.version
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let total = match self.state {
+            OtaState::Downloading { total, .. } => total,
+            _ => anyhow::bail!("Update is not in progress"),
+        };
+        if self.written + data.len() > total {
+            return self.fail(anyhow::anyhow!("Image is larger than {} bytes", total));
+        }
+        if let Err(e) = self.writer.write(data) {
+            return self.fail(e);
+        }
+        self.hasher.update(data);
+        self.written += data.len();
+        self.state = OtaState::Downloading {
+            written: self.written,
+            total,
+        };
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if !matches!(self.state, OtaState::Downloading { .. }) {
+            anyhow::bail!("Update is not in progress");
+        }
+        self.state = OtaState::Verifying;
+
+        if self.written != self.image.size as usize {
+            let e = anyhow::anyhow!("Incomplete image: {}/{}", self.written, self.image.size);
+            return self.fail(e);
+        }
+        let digest = std::mem::take(&mut self.hasher).finalize();
+        if digest.as_slice() != self.image.sha256.as_slice() {
+            return self.fail(anyhow::anyhow!("Firmware hash mismatch"));
+        }
+        if let Err(e) = verify_signature(&digest, self.image.signature.as_deref()) {
+            return self.fail(e);
+        }
+        if let Err(e) = self.writer.finish() {
+            return self.fail(e);
+        }
+
+        self.state = OtaState::Ready;
+        log::info!("Firmware {} ready", self.image.version);
+        Ok(())
+    }
+
+    pub fn abort(&mut self) {
+        if !matches!(self.state, OtaState::Ready | OtaState::Failed(_)) {
+            self.writer.abort();
+            self.state = OtaState::Failed("Aborted".to_string());
+        }
+    }
+
+    fn fail(&mut self, e: anyhow::Error) -> anyhow::Result<()> {
+        log::error!("Firmware update failed: {:?}", e);
+        self.writer.abort();
+        self.state = OtaState::Failed(e.to_string());
+        Err(e)
+    }
+}
+
+/// An update dropped before it finished, e.g. when the server goes away,
+/// releases the slot.
+impl<W: FlashWriter> Drop for Updater<W> {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+fn verify_signature(digest: &[u8], signature: Option<&[u8]>) -> anyhow::Result<()> {
+    let Some(pubkey) = OTA_PUBKEY else {
+        return Ok(());
+    };
+    verify_ed25519(pubkey, digest, signature.unwrap_or_default())
+        .map_err(|e| anyhow::anyhow!("Invalid firmware signature: {}", e))
+}
+
+/// Checks an ed25519 signature with a hex encoded public key, as the keys
+/// are given at build time.
+pub fn verify_ed25519(pubkey: &str, message: &[u8], signature: &[u8]) -> anyhow::Result<()> {
+    let pubkey = (0..pubkey.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(pubkey.get(i..i + 2).unwrap_or("zz"), 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow::anyhow!("invalid public key"))?;
+    let pubkey = ed25519_compact::PublicKey::from_slice(&pubkey)?;
+    let signature = ed25519_compact::Signature::from_slice(signature)?;
+    pubkey
+        .verify(message, &signature)
+        .map_err(|_| anyhow::anyhow!("signature mismatch"))
+}
+
+#[test]
+fn test_updater() {
+    struct MockFlash(Vec<u8>, bool);
+
+    impl FlashWriter for MockFlash {
+        fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            self.0.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> anyhow::Result<()> {
+            self.1 = true;
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    let data = vec![0x5a; 1000];
+    let image = FirmwareImage {
+        version: "0.2.0".to_string(),
+        size: data.len() as u32,
+        sha256: sha2::Sha256::digest(&data).to_vec(),
+        signature: None,
+    };
+
+    // no slot is opened for an image that can't be verified
+    let empty = FirmwareImage {
+        size: 0,
+        ..image.clone()
+    };
+    assert!(Updater::<MockFlash>::new(empty, |_| panic!("slot opened")).is_err());
+
+    let mut updater = Updater::new(image.clone(), |_| Ok(MockFlash(vec![], false))).unwrap();
+    for chunk in data.chunks(300) {
+        updater.write(chunk).unwrap();
+    }
+    assert!(updater.write(&[0]).is_err());
+    assert!(matches!(updater.state(), OtaState::Failed(_)));
+
+    let mut updater = Updater::new(image.clone(), |_| Ok(MockFlash(vec![], false))).unwrap();
+    updater.write(&data).unwrap();
+    updater.finish().unwrap();
+    assert_eq!(updater.state(), &OtaState::Ready);
+    assert!(updater.writer.1);
+    assert_eq!(updater.writer.0, data);
+
+    let bad = FirmwareImage {
+        sha256: vec![0; 32],
+        ..image
+    };
+    let mut updater = Updater::new(bad, |_| Ok(MockFlash(vec![], false))).unwrap();
+    updater.write(&data).unwrap();
+    assert!(updater.finish().is_err());
+    assert!(!updater.writer.1);
+    assert!(updater.writer.0.is_empty());
+}
+
+#[test]
+fn test_updater_drop() {
+    use std::{cell::Cell, rc::Rc};
+
+    struct AbortFlash(Rc<Cell<u32>>);
+
+    impl FlashWriter for AbortFlash {
+        fn write(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn finish(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    let data = vec![0x5a; 100];
+    let image = FirmwareImage {
+        version: "0.2.0".to_string(),
+        size: data.len() as u32,
+        sha256: sha2::Sha256::digest(&data).to_vec(),
+        signature: None,
+    };
+    let aborts = Rc::new(Cell::new(0));
+
+    // dropped halfway
+    let mut updater = Updater::new(image.clone(), |_| Ok(AbortFlash(aborts.clone()))).unwrap();
+    updater.write(&data[..50]).unwrap();
+    drop(updater);
+    assert_eq!(aborts.get(), 1);
+
+    // already aborted, or finished
+    let mut updater = Updater::new(image.clone(), |_| Ok(AbortFlash(aborts.clone()))).unwrap();
+    updater.abort();
+    drop(updater);
+    let mut updater = Updater::new(image, |_| Ok(AbortFlash(aborts.clone()))).unwrap();
+    updater.write(&data).unwrap();
+    updater.finish().unwrap();
+    drop(updater);
+    assert_eq!(aborts.get(), 2);
+}
+
+#[test]
+fn test_readiness() {
+    Readiness::default().check().unwrap();
+    for readiness in [
+        Readiness {
+            busy: true,
+            ..Default::default()
+        },
+        Readiness {
+            transferring: true,
+            ..Default::default()
+        },
+        Readiness {
+            brownout: true,
+            ..Default::default()
+        },
+    ] {
+        assert!(readiness.check().is_err());
+    }
+}
+
+#[test]
+fn test_is_newer() {
+    assert!(is_newer("0.1.0", "0.1.1"));
+    assert!(is_newer("0.1.9", "0.2.0"));
+    assert!(is_newer("0.1.0", "v1.0.0"));
+    assert!(!is_newer("0.1.0", "0.1.0"));
+    assert!(!is_newer("0.2.0", "0.1.10"));
+    assert!(!is_newer("0.1.0", "garbage"));
+}
@@ -0,0 +1,113 @@
+use std::net::Ipv4Addr;
+
+use serde::Deserialize;
+
+use crate::settings::{self, Value};
+
+/// Posted as JSON by `assets/index.html`.
+#[derive(Deserialize)]
+pub struct Form {
+    #[serde(rename = "wifi_username")]
+    pub ssid: String,
+    #[serde(rename = "wifi_password", default)]
+    pub pass: String,
+    pub server_url: String,
+}
+
+impl Form {
+    /// Returns the server URL to store. The network is checked when it is
+    /// added to the saved networks.
+    pub fn validate(&self) -> anyhow::Result<Value> {
+        if self.ssid.is_empty() {
+            anyhow::bail!("WiFi SSID is empty");
+        }
+        settings::def("server_url")?.validate(Value::Text(self.server_url.clone()))
+    }
+}
+
+/// Answers every A query with `ip`, which sends the connectivity checks of
+/// phones and laptops to the portal so they open it on their own. Other
+/// queries get an empty answer.
+pub fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    let header = query.get(..12)?;
+    // only standard queries with a single question
+    if header[2] & 0xf8 != 0 || header[4..6] != [0, 1] {
+        return None;
+    }
+    let mut i = 12;
+    loop {
+        let len = *query.get(i)? as usize;
+        i += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        i += len;
+    }
+    let question = query.get(12..i + 4)?;
+    let is_a = question[question.len() - 4..] == [0, 1, 0, 1];
+
+    let mut reply = Vec::with_capacity(12 + question.len() + 16);
+    reply.extend_from_slice(&header[..2]);
+    reply.push(0x84 | (header[2] & 0x01)); // response, authoritative, keep RD
+    reply.push(0x80); // recursion available, no error
+    reply.extend_from_slice(&[0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if is_a {
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]); // the name above, A, IN
+        reply.extend_from_slice(&60u32.to_be_bytes());
+        reply.extend_from_slice(&[0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+#[test]
+fn test_portal_form() {
+    let form: Form = serde_json::from_str(
+        r#"{"wifi_username":"home","wifi_password":"12345678","server_url":"ws://echokit.dev/ws"}"#,
+    )
+    .unwrap();
+    assert_eq!(form.ssid, "home");
+    assert_eq!(
+        form.validate().unwrap(),
+        Value::Text("ws://echokit.dev/ws/".to_string())
+    );
+
+    let form: Form =
+        serde_json::from_str(r#"{"wifi_username":"","server_url":"ws://a/"}"#).unwrap();
+    assert!(form.validate().is_err());
+    let form: Form =
+        serde_json::from_str(r#"{"wifi_username":"home","server_url":"http://a/"}"#).unwrap();
+    assert!(form.validate().is_err());
+}
+
+#[test]
+fn test_dns_reply() {
+    let ip = Ipv4Addr::new(192, 168, 71, 1);
+    let query = |qtype: u8| {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        q.extend_from_slice(b"\x07example\x03com\x00");
+        q.extend_from_slice(&[0, qtype, 0, 1]);
+        q
+    };
+
+    let reply = dns_reply(&query(1), ip).unwrap();
+    assert_eq!(&reply[..4], &[0x12, 0x34, 0x85, 0x80]);
+    assert_eq!(&reply[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(&reply[12..29], &query(1)[12..]);
+    assert_eq!(&reply[reply.len() - 4..], &[192, 168, 71, 1]);
+
+    // AAAA gets no answer, so clients fall back to the A record
+    let reply = dns_reply(&query(28), ip).unwrap();
+    assert_eq!(&reply[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(reply.len(), query(28).len());
+
+    let mut response = query(1);
+    response[2] |= 0x80;
+    assert!(dns_reply(&response, ip).is_none());
+    assert!(dns_reply(&query(1)[..20], ip).is_none());
+    assert!(dns_reply(&[0; 4], ip).is_none());
+}
@@ -0,0 +1,318 @@
+use serde::{Deserialize, Serialize};
+
+/// Wire encoding of [`ServerEvent`] frames.
+///
+/// The device offers both encodings as WebSocket subprotocols during the
+/// handshake and the server picks one. Servers that don't answer with a
+/// subprotocol get the original msgpack behaviour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// msgpack in binary frames
+    MsgPack,
+    /// JSON in text frames, byte fields as base64 strings
+    Json,
+}
+
+impl Encoding {
+    pub const fn subprotocol(&self) -> &'static str {
+        match self {
+            Encoding::MsgPack => "echokit.msgpack",
+            Encoding::Json => "echokit.json",
+        }
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        match name.trim() {
+            "echokit.msgpack" => Some(Encoding::MsgPack),
+            "echokit.json" => Some(Encoding::Json),
+            _ => None,
+        }
+    }
+
+    /// Decodes a data frame, which has to be in the negotiated encoding:
+    /// msgpack in binary frames or JSON in text frames.
+    pub fn decode(&self, payload: &[u8], binary: bool) -> anyhow::Result<ServerEvent> {
+        match (self, binary) {
+            (Encoding::MsgPack, true) => ServerEvent::from_msgpack(payload),
+            (Encoding::Json, false) => ServerEvent::from_json(std::str::from_utf8(payload)?),
+            (_, true) => anyhow::bail!("Binary frame, but {:?} was negotiated", self),
+            (_, false) => anyhow::bail!("Text frame, but {:?} was negotiated", self),
+        }
+    }
+}
+
+/// Assets that can be replaced through the transfer sub-protocol.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    Hello,
+    Background,
+    Sound,
+}
+
+/// Integrity check for a whole transferred asset.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Crc32(u32),
+    Sha256(#[serde(with = "base64_bytes")] Vec<u8>),
+}
+
+/// A firmware image as announced by the server.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FirmwareImage {
+    pub version: String,
+    pub size: u32,
+    #[serde(with = "base64_bytes")]
+    pub sha256: Vec<u8>,
+    /// ed25519 signature over `sha256`, required when the firmware is built
+    /// with `ECHOKIT_OTA_PUBKEY`
+    #[serde(default, with = "base64_opt_bytes")]
+    pub signature: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ServerEvent {
+    // set Hello
+    HelloStart,
+    HelloChunk {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    HelloEnd,
+    /// drop the custom hello and go back to the built-in one
+    HelloReset,
+
+    // set Background
+    BGStart,
+    BGChunk {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    BGEnd,
+
+    // resumable asset transfer, see `crate::transfer`
+    TransferStart {
+        id: u32,
+        kind: AssetKind,
+        /// stored under the default name for `kind` if not set
+        #[serde(default)]
+        name: Option<String>,
+        size: u32,
+        checksum: Checksum,
+    },
+    TransferChunk {
+        id: u32,
+        offset: u32,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    TransferCommit {
+        id: u32,
+    },
+    TransferAbort {
+        id: u32,
+    },
+    /// switch to a stored asset, `None` for the built-in one
+    SelectAsset {
+        kind: AssetKind,
+        name: Option<String>,
+    },
+
+    // download and install a firmware image over HTTP(S), answered by
+    // FirmwareReject when the device can't take it now or FirmwareResult
+    // when the download fails
+    FirmwareUpdate {
+        url: String,
+        image: FirmwareImage,
+    },
+    // stream a firmware image over this socket, answered by FirmwareAccept
+    // or FirmwareReject
+    FirmwareOffer {
+        image: FirmwareImage,
+    },
+    FirmwareChunk {
+        offset: u32,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    FirmwareEnd,
+    FirmwareAbort,
+
+    ASR {
+        text: String,
+    },
+    Action {
+        action: String,
+    },
+    StartAudio {
+        text: String,
+    },
+    AudioChunk {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    EndAudio,
+    StartVideo,
+    EndVideo,
+    EndResponse,
+}
+
+impl ServerEvent {
+    pub fn from_msgpack(data: &[u8]) -> anyhow::Result<Self> {
+        rmp_serde::from_slice(data)
+            .map_err(|e| anyhow::anyhow!("Failed to deserialize binary data: {}", e))
+    }
+
+    pub fn from_json(text: &str) -> anyhow::Result<Self> {
+        serde_json::from_str(text)
+            .map_err(|e| anyhow::anyhow!("Failed to deserialize text data: {}", e))
+    }
+}
+
+/// Events sent from the device to the server.
+///
+/// Binary frames from the device carry microphone audio, so these always go
+/// out as JSON text frames. They are JSON objects, which keeps them apart from
+/// the plain `End:*` text frames.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ClientEvent {
+    /// Next byte the device expects for a transfer. Sent after `TransferStart`,
+    /// after an out-of-order chunk and after reconnecting mid-transfer.
+    TransferAck {
+        id: u32,
+        offset: u32,
+    },
+    TransferDone {
+        id: u32,
+    },
+    TransferError {
+        id: u32,
+        error: String,
+    },
+    FirmwareAccept {
+        version: String,
+    },
+    FirmwareReject {
+        version: String,
+        reason: String,
+    },
+    /// Bytes written so far. Sent every 64KB and after an out-of-order chunk,
+    /// in which case the server should continue from `offset`.
+    FirmwareProgress {
+        offset: u32,
+    },
+    FirmwareResult {
+        version: String,
+        error: Option<String>,
+    },
+}
+
+impl ClientEvent {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Byte fields are plain `Vec<u8>` for msgpack (unchanged on the wire) and
+/// base64 strings for human readable formats such as JSON.
+pub(crate) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(data))
+        } else {
+            data.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            STANDARD.decode(s.as_bytes()).map_err(D::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer)
+        }
+    }
+}
+
+pub(crate) mod base64_opt_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(transparent)]
+    struct Bytes(#[serde(with = "super::base64_bytes")] Vec<u8>);
+
+    pub fn serialize<S: Serializer>(
+        data: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        data.as_ref()
+            .map(|d| Bytes(d.clone()))
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<Bytes>::deserialize(deserializer)?.map(|b| b.0))
+    }
+}
+
+#[test]
+fn test_rmp_command() {
+    let event = ServerEvent::Action {
+        action: "say".to_string(),
+    };
+    let data = rmp_serde::to_vec(&event).unwrap();
+    println!("Serialized data: {:?}", data);
+    println!("Serialized data: {}", String::from_utf8_lossy(&data));
+    let data = rmp_serde::to_vec_named(&event).unwrap();
+    println!("Serialized data: {:?}", data);
+    println!("Serialized data: {}", String::from_utf8_lossy(&data));
+    let cmd: ServerEvent = rmp_serde::from_slice(&data).unwrap();
+    match cmd {
+        ServerEvent::Action { action } => {
+            assert_eq!(action, "say");
+        }
+        _ => panic!("Unexpected command: {:?}", cmd),
+    }
+}
+
+#[test]
+fn test_json_command() {
+    let cmd = ServerEvent::from_json(r#"{"AudioChunk":{"data":"AQID"}}"#).unwrap();
+    match cmd {
+        ServerEvent::AudioChunk { data } => {
+            assert_eq!(data, vec![1, 2, 3]);
+        }
+        _ => panic!("Unexpected command: {:?}", cmd),
+    }
+
+    let cmd = ServerEvent::from_json(r#""EndAudio""#).unwrap();
+    assert!(matches!(cmd, ServerEvent::EndAudio));
+
+    let data = rmp_serde::to_vec(&ServerEvent::HelloChunk {
+        data: vec![1, 2, 3],
+    })
+    .unwrap();
+    let cmd = ServerEvent::from_msgpack(&data).unwrap();
+    assert!(matches!(cmd, ServerEvent::HelloChunk { data } if data == vec![1, 2, 3]));
+}
+
+#[test]
+fn test_encoding_decode() {
+    let data = rmp_serde::to_vec(&ServerEvent::EndAudio).unwrap();
+    assert!(matches!(
+        Encoding::MsgPack.decode(&data, true).unwrap(),
+        ServerEvent::EndAudio
+    ));
+    assert!(Encoding::Json.decode(&data, true).is_err());
+
+    let text = br#""EndAudio""#;
+    assert!(matches!(
+        Encoding::Json.decode(text, false).unwrap(),
+        ServerEvent::EndAudio
+    ));
+    assert!(Encoding::MsgPack.decode(text, false).is_err());
+}
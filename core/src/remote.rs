@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+/// Longest transcript kept in the status, so it fits in one read.
+const MAX_TRANSCRIPT_LEN: usize = 200;
+
+/// A command written to the remote control characteristic, as JSON or
+/// msgpack, e.g. `{"cmd":"volume","value":60}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    Volume {
+        value: u8,
+    },
+    Mute {
+        value: bool,
+    },
+    /// Starts recording, the same as a long press on K0.
+    PushToTalk,
+    /// Stops listening, recording or speaking.
+    Idle,
+}
+
+impl Command {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.first() == Some(&b'{') {
+            Ok(serde_json::from_slice(data)?)
+        } else {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+    }
+}
+
+/// Read and notified as JSON by the remote control service.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Status {
+    pub state: &'static str,
+    pub volume: u8,
+    pub muted: bool,
+    pub transcript: String,
+}
+
+impl Status {
+    pub fn set_transcript(&mut self, text: &str) {
+        let mut end = text.len().min(MAX_TRANSCRIPT_LEN);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.transcript = text[..end].to_string();
+    }
+}
+
+#[test]
+fn test_remote_command() {
+    assert_eq!(
+        Command::parse(br#"{"cmd":"volume","value":60}"#).unwrap(),
+        Command::Volume { value: 60 }
+    );
+    assert_eq!(
+        Command::parse(br#"{"cmd":"push_to_talk"}"#).unwrap(),
+        Command::PushToTalk
+    );
+    assert!(Command::parse(br#"{"cmd":"volume","value":300}"#).is_err());
+    assert!(Command::parse(br#"{"cmd":"reboot"}"#).is_err());
+
+    let mut status = Status::default();
+    status.set_transcript(&"你好".repeat(100));
+    assert!(status.transcript.len() <= MAX_TRANSCRIPT_LEN);
+    assert!(status.transcript.starts_with("你好"));
+}
@@ -0,0 +1,271 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+
+use crate::settings::{self, Kind, Storage, Value};
+
+/// Blobs that hold secrets, sealed like the secret settings. The saved
+/// networks carry the Wi-Fi passwords.
+const SECRET_BLOBS: &[&str] = &[crate::networks::NVS_KEY];
+/// Starts every sealed value, plaintext JSON and settings never do.
+const MAGIC: &[u8] = b"\0SL1";
+const NONCE_LEN: usize = 12;
+
+fn is_secret_setting(key: &str) -> bool {
+    settings::def(key).is_ok_and(|def| def.secret)
+}
+
+/// Seals secrets with AES-256-GCM on their way into `storage`, so a dump of
+/// the flash doesn't give them away. The name of the entry is authenticated
+/// too, a sealed value copied to another key doesn't open.
+///
+/// Secret settings are sealed as JSON into a blob under their own key. Values
+/// that were stored before sealing are still read, [`Sealed::seal_all`] seals
+/// them.
+pub struct Sealed<S> {
+    storage: S,
+    /// `None` where there is no key, then secrets are stored as they are.
+    cipher: Option<Aes256Gcm>,
+}
+
+impl<S: Storage> Sealed<S> {
+    pub fn new(storage: S, key: Option<[u8; 32]>) -> Self {
+        Self {
+            storage,
+            cipher: key.map(|key| Aes256Gcm::new(&key.into())),
+        }
+    }
+
+    fn seal(&self, key: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(data.to_vec());
+        };
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let payload = Payload {
+            msg: data,
+            aad: key.as_bytes(),
+        };
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow::anyhow!("Failed to seal {}", key))?;
+        Ok([MAGIC, &nonce, &sealed].concat())
+    }
+
+    fn open(&self, key: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let Some(data) = data.strip_prefix(MAGIC) else {
+            return Ok(data);
+        };
+        let Some(cipher) = &self.cipher else {
+            anyhow::bail!("{} is sealed, but there is no key", key);
+        };
+        if data.len() < NONCE_LEN {
+            anyhow::bail!("Sealed {} is too short", key);
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: sealed,
+            aad: key.as_bytes(),
+        };
+        cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow::anyhow!("Failed to open sealed {}", key))
+    }
+
+    /// Seals what isn't sealed yet: the secret blobs and the secret settings
+    /// stored as plain values by older firmware or before there was a key.
+    /// Runs at every boot, after `settings::migrate`; without a key it has
+    /// nothing to do.
+    pub fn seal_all(&mut self) -> anyhow::Result<()> {
+        if self.cipher.is_none() {
+            return Ok(());
+        }
+        for key in SECRET_BLOBS {
+            match self.storage.get_blob(key)? {
+                Some(data) if !data.starts_with(MAGIC) => self.set_blob(key, &data)?,
+                _ => {}
+            }
+        }
+        for def in settings::SCHEMA.iter().filter(|def| def.secret) {
+            if let Some(value) = self.storage.get(def.key, def.kind)? {
+                self.storage.remove(def.key)?;
+                self.set(def.key, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for Sealed<S> {
+    fn get(&self, key: &str, kind: Kind) -> anyhow::Result<Option<Value>> {
+        if is_secret_setting(key) {
+            if let Some(data) = self.storage.get_blob(key)? {
+                return Ok(Some(serde_json::from_slice(&self.open(key, data)?)?));
+            }
+        }
+        self.storage.get(key, kind)
+    }
+
+    fn set(&mut self, key: &str, value: &Value) -> anyhow::Result<()> {
+        if is_secret_setting(key) && self.cipher.is_some() {
+            let sealed = self.seal(key, &serde_json::to_vec(value)?)?;
+            return self.storage.set_blob(key, &sealed);
+        }
+        self.storage.set(key, value)
+    }
+
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.storage.get_blob(key)? {
+            Some(data) if SECRET_BLOBS.contains(&key) => Ok(Some(self.open(key, data)?)),
+            data => Ok(data),
+        }
+    }
+
+    fn set_blob(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        if SECRET_BLOBS.contains(&key) {
+            let sealed = self.seal(key, data)?;
+            return self.storage.set_blob(key, &sealed);
+        }
+        self.storage.set_blob(key, data)
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        self.storage.remove(key)
+    }
+}
+
+#[test]
+fn test_sealed_storage() {
+    use crate::networks::{self, KnownNetwork, Networks};
+    use settings::MemStorage;
+
+    let mut networks = Networks::default();
+    networks
+        .add(KnownNetwork {
+            ssid: "home".to_string(),
+            pass: "hunter2hunter2".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+
+    let mut storage = Sealed::new(MemStorage::default(), Some([7; 32]));
+    networks::save(&mut storage, &networks).unwrap();
+    settings::save(
+        &mut storage,
+        settings::def("volume").unwrap(),
+        &Value::Integer(40),
+    )
+    .unwrap();
+    assert_eq!(networks::load(&storage).list()[0].pass, "hunter2hunter2");
+
+    // only secrets are sealed
+    let raw = storage
+        .storage
+        .get_blob(networks::NVS_KEY)
+        .unwrap()
+        .unwrap();
+    assert!(raw.starts_with(MAGIC));
+    assert!(!String::from_utf8_lossy(&raw).contains("hunter2"));
+    assert_eq!(
+        storage.storage.get("volume", Kind::Bool).unwrap(),
+        Some(Value::Integer(40))
+    );
+
+    // sealed to the key and to the entry name
+    let other = Sealed::new(storage.storage.clone(), Some([8; 32]));
+    assert!(other.get_blob(networks::NVS_KEY).is_err());
+    let no_key = Sealed::new(storage.storage.clone(), None);
+    assert!(no_key.get_blob(networks::NVS_KEY).is_err());
+    assert!(storage.open("ip_config", raw).is_err());
+}
+
+#[test]
+fn test_seal_all() {
+    use crate::networks;
+
+    // networks as older firmware stored them
+    let mut plain = settings::MemStorage::default();
+    plain.set("settings_ver", &Value::Integer(1)).unwrap();
+    plain
+        .set_blob(networks::NVS_KEY, br#"[{"ssid":"home","pass":"12345678"}]"#)
+        .unwrap();
+
+    let mut storage = Sealed::new(plain.clone(), Some([7; 32]));
+    assert_eq!(networks::load(&storage).list()[0].pass, "12345678");
+    storage.seal_all().unwrap();
+    let raw = storage
+        .storage
+        .get_blob(networks::NVS_KEY)
+        .unwrap()
+        .unwrap();
+    assert!(raw.starts_with(MAGIC));
+    assert_eq!(networks::load(&storage).list()[0].pass, "12345678");
+    // sealed values are left alone
+    storage.seal_all().unwrap();
+    assert_eq!(
+        storage.storage.get_blob(networks::NVS_KEY).unwrap(),
+        Some(raw)
+    );
+
+    // without a key the device keeps working as before
+    let mut storage = Sealed::new(plain.clone(), None);
+    settings::migrate(&mut storage).unwrap();
+    storage.seal_all().unwrap();
+    assert_eq!(
+        storage.storage.get_blob(networks::NVS_KEY).unwrap(),
+        plain.get_blob(networks::NVS_KEY).unwrap()
+    );
+
+    // a key that shows up after the migrations ran still seals them
+    let mut storage = Sealed::new(storage.storage, Some([7; 32]));
+    assert_eq!(settings::migrate(&mut storage).unwrap(), settings::VERSION);
+    storage.seal_all().unwrap();
+    let raw = storage
+        .storage
+        .get_blob(networks::NVS_KEY)
+        .unwrap()
+        .unwrap();
+    assert!(raw.starts_with(MAGIC));
+    assert_eq!(networks::load(&storage).list()[0].pass, "12345678");
+}
+
+#[test]
+fn test_sealed_settings() {
+    use settings::MemStorage;
+
+    let token = settings::def("token").unwrap();
+    assert!(token.secret);
+    let value = Value::Text("t0ken-t0ken".to_string());
+
+    let mut storage = Sealed::new(MemStorage::default(), Some([7; 32]));
+    settings::save(&mut storage, token, &value).unwrap();
+    assert_eq!(settings::load(&storage, token), Some(value.clone()));
+    assert_eq!(settings::Settings::load(&storage).token, "t0ken-t0ken");
+    // sealed into a blob, nothing in the clear
+    assert_eq!(storage.storage.get("token", token.kind).unwrap(), None);
+    let raw = storage.storage.get_blob("token").unwrap().unwrap();
+    assert!(raw.starts_with(MAGIC));
+    assert!(!String::from_utf8_lossy(&raw).contains("t0ken"));
+    // and never listed with its value
+    let schema =
+        String::from_utf8(settings::schema_json(|def| settings::load(&storage, def))).unwrap();
+    assert!(!schema.contains("t0ken"));
+    let settings = settings::Settings::load(&storage);
+    assert!(!format!("{:?}", settings).contains("t0ken"));
+
+    // a token older firmware stored in the clear is sealed at boot
+    let mut plain = MemStorage::default();
+    plain.set("settings_ver", &Value::Integer(1)).unwrap();
+    plain.set("token", &value).unwrap();
+    let mut storage = Sealed::new(plain, Some([7; 32]));
+    assert_eq!(settings::load(&storage, token), Some(value.clone()));
+    storage.seal_all().unwrap();
+    assert_eq!(storage.storage.get("token", token.kind).unwrap(), None);
+    assert!(storage
+        .storage
+        .get_blob("token")
+        .unwrap()
+        .unwrap()
+        .starts_with(MAGIC));
+    assert_eq!(settings::load(&storage, token), Some(value));
+}
@@ -0,0 +1,551 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Bumped with every entry in `MIGRATIONS`.
+pub const VERSION: i64 = 1;
+const VERSION_KEY: &str = "settings_ver";
+
+/// Type and constraints of a setting, listed in the schema.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Kind {
+    Text { max_len: usize },
+    Integer { min: i64, max: i64 },
+    Bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Integer(i64),
+    Text(String),
+}
+
+/// A setting that can be read and written by key, e.g. through the BLE
+/// settings characteristic, without any code of its own.
+#[derive(Debug, Serialize)]
+pub struct Def {
+    pub key: &'static str,
+    #[serde(flatten)]
+    pub kind: Kind,
+    /// Write-only, the value is never read back.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+    /// Used while the setting isn't stored, written as it would be typed on
+    /// the console (see `parse_str`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<&'static str>,
+    /// Extra validation after the type check, may normalize the value.
+    #[serde(skip)]
+    pub check: Option<fn(Value) -> anyhow::Result<Value>>,
+}
+
+pub const SCHEMA: &[Def] = &[
+    Def {
+        key: "server_url",
+        kind: Kind::Text { max_len: 127 },
+        secret: false,
+        default: None,
+        check: Some(check_server_url),
+    },
+    Def {
+        key: "volume",
+        kind: Kind::Integer { min: 0, max: 100 },
+        secret: false,
+        default: Some("100"),
+        check: None,
+    },
+    // Keeps the BLE remote control service running after setup.
+    Def {
+        key: "ble_remote",
+        kind: Kind::Bool,
+        secret: false,
+        default: Some("off"),
+        check: None,
+    },
+    // Sent as `Authorization: Bearer <token>` when connecting to the server.
+    Def {
+        key: "token",
+        kind: Kind::Text { max_len: 255 },
+        secret: true,
+        default: None,
+        check: None,
+    },
+    // Sent as `Accept-Language`, e.g. "en" or "zh-CN". The server picks when
+    // it is empty.
+    Def {
+        key: "language",
+        kind: Kind::Text { max_len: 16 },
+        secret: false,
+        default: None,
+        check: Some(check_language),
+    },
+];
+
+fn check_server_url(value: Value) -> anyhow::Result<Value> {
+    let Value::Text(mut url) = value else {
+        anyhow::bail!("Server URL must be text");
+    };
+    if !url.starts_with("ws://") && !url.starts_with("wss://") {
+        anyhow::bail!("Server URL must start with ws:// or wss://");
+    }
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    Ok(Value::Text(url))
+}
+
+/// A language tag like "en" or "zh-CN", or nothing.
+fn check_language(value: Value) -> anyhow::Result<Value> {
+    let Value::Text(tag) = &value else {
+        anyhow::bail!("Language must be text");
+    };
+    let valid = tag.split('-').all(|part| {
+        (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+    });
+    if !tag.is_empty() && !valid {
+        anyhow::bail!("Invalid language tag: {:?}", tag);
+    }
+    Ok(value)
+}
+
+pub fn def(key: &str) -> anyhow::Result<&'static Def> {
+    SCHEMA
+        .iter()
+        .find(|d| d.key == key)
+        .ok_or_else(|| anyhow::anyhow!("Unknown setting {}", key))
+}
+
+impl Def {
+    /// Returns the value to store.
+    pub fn validate(&self, value: Value) -> anyhow::Result<Value> {
+        let value = match (self.kind, value) {
+            (Kind::Text { max_len }, Value::Text(s)) => {
+                if s.len() > max_len {
+                    anyhow::bail!("{} is longer than {} bytes", self.key, max_len);
+                }
+                Value::Text(s)
+            }
+            (Kind::Integer { min, max }, Value::Integer(i)) => {
+                if i < min || i > max {
+                    anyhow::bail!("{} must be between {} and {}", self.key, min, max);
+                }
+                Value::Integer(i)
+            }
+            (Kind::Bool, Value::Bool(b)) => Value::Bool(b),
+            (kind, value) => anyhow::bail!("{} must be {:?}, got {:?}", self.key, kind, value),
+        };
+        match self.check {
+            Some(check) => check(value),
+            None => Ok(value),
+        }
+    }
+
+    /// Parses a value typed as text, e.g. on the serial console, and
+    /// validates it.
+    pub fn parse_str(&self, s: &str) -> anyhow::Result<Value> {
+        let value = match self.kind {
+            Kind::Text { .. } => Value::Text(s.to_string()),
+            Kind::Integer { .. } => Value::Integer(
+                s.parse()
+                    .map_err(|_| anyhow::anyhow!("{} must be a number", self.key))?,
+            ),
+            Kind::Bool => match s {
+                "true" | "on" | "1" => Value::Bool(true),
+                "false" | "off" | "0" => Value::Bool(false),
+                _ => anyhow::bail!("{} must be on or off", self.key),
+            },
+        };
+        self.validate(value)
+    }
+}
+
+/// A `{key, value}` write, as JSON or msgpack.
+#[derive(Debug, Deserialize)]
+pub struct Write {
+    pub key: String,
+    pub value: Value,
+}
+
+impl Write {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.first() == Some(&b'{') {
+            Ok(serde_json::from_slice(data)?)
+        } else {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+    }
+
+    /// Returns the definition of the setting and the validated value.
+    pub fn validate(self) -> anyhow::Result<(&'static Def, Value)> {
+        let def = def(&self.key)?;
+        Ok((def, def.validate(self.value)?))
+    }
+}
+
+/// The schema as a JSON list, with the current value of every setting that
+/// isn't secret, e.g.
+/// `[{"key":"server_url","type":"text","max_len":127,"value":"ws://..."}]`.
+pub fn schema_json(current: impl Fn(&Def) -> Option<Value>) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Entry<'a> {
+        #[serde(flatten)]
+        def: &'a Def,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<Value>,
+    }
+
+    let entries = SCHEMA
+        .iter()
+        .map(|def| Entry {
+            def,
+            value: if def.secret { None } else { current(def) },
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_vec(&entries).unwrap_or_default()
+}
+
+/// Key/value storage the settings are kept in, NVS on the device.
+pub trait Storage {
+    /// `kind` tells how the value is stored.
+    fn get(&self, key: &str, kind: Kind) -> anyhow::Result<Option<Value>>;
+    fn set(&mut self, key: &str, value: &Value) -> anyhow::Result<()>;
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn set_blob(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()>;
+    /// Does nothing if `key` isn't stored.
+    fn remove(&mut self, key: &str) -> anyhow::Result<()>;
+}
+
+/// Settings in memory, which can be saved and loaded as JSON.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemStorage {
+    values: BTreeMap<String, Value>,
+    #[serde(default)]
+    blobs: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemStorage {
+    pub fn from_json(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+}
+
+impl Storage for MemStorage {
+    fn get(&self, key: &str, _kind: Kind) -> anyhow::Result<Option<Value>> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &Value) -> anyhow::Result<()> {
+        self.values.insert(key.to_string(), value.clone());
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.blobs.get(key).cloned())
+    }
+
+    fn set_blob(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.blobs.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        self.values.remove(key);
+        self.blobs.remove(key);
+        Ok(())
+    }
+}
+
+/// Reads a setting, falling back to its default. A stored value that can't
+/// be read or no longer validates is logged and skipped.
+pub fn load(storage: &dyn Storage, def: &Def) -> Option<Value> {
+    let stored = storage
+        .get(def.key, def.kind)
+        .and_then(|v| v.map(|v| def.validate(v)).transpose());
+    match stored {
+        Ok(Some(value)) => return Some(value),
+        Ok(None) => {}
+        Err(e) => log::error!("Failed to load {}: {:?}", def.key, e),
+    }
+    def.default.and_then(|s| {
+        def.parse_str(s)
+            .map_err(|e| log::error!("Invalid default for {}: {:?}", def.key, e))
+            .ok()
+    })
+}
+
+pub fn save(storage: &mut dyn Storage, def: &Def, value: &Value) -> anyhow::Result<()> {
+    let value = def.validate(value.clone())?;
+    storage.set(def.key, &value)
+}
+
+/// The settings in `SCHEMA` as typed fields, kept in sync with every write.
+#[derive(Clone, PartialEq)]
+pub struct Settings {
+    /// Empty until the device is set up.
+    pub server_url: String,
+    pub volume: u8,
+    pub ble_remote: bool,
+    /// Empty when the server needs none.
+    pub token: String,
+    pub language: String,
+}
+
+/// Leaves out the token, settings end up in the log.
+impl std::fmt::Debug for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Settings")
+            .field("server_url", &self.server_url)
+            .field("volume", &self.volume)
+            .field("ble_remote", &self.ble_remote)
+            .field("language", &self.language)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Settings {
+    pub fn load(storage: &dyn Storage) -> Self {
+        let mut settings = Settings {
+            server_url: String::new(),
+            volume: 100,
+            ble_remote: false,
+            token: String::new(),
+            language: String::new(),
+        };
+        for def in SCHEMA {
+            if let Some(value) = load(storage, def) {
+                settings.apply(def.key, &value);
+            }
+        }
+        settings
+    }
+
+    pub fn apply(&mut self, key: &str, value: &Value) {
+        match (key, value) {
+            ("server_url", Value::Text(url)) => self.server_url = url.clone(),
+            ("volume", Value::Integer(volume)) => self.volume = *volume as u8,
+            ("ble_remote", Value::Bool(on)) => self.ble_remote = *on,
+            ("token", Value::Text(token)) => self.token = token.clone(),
+            ("language", Value::Text(language)) => self.language = language.clone(),
+            _ => log::warn!("Unexpected setting {} = {:?}", key, value),
+        }
+    }
+}
+
+/// `MIGRATIONS[i]` moves the stored settings from version `i` to `i + 1`.
+/// Sealing the secrets isn't one, a key can show up after the migrations
+/// ran, see `secrets::Sealed::seal_all`.
+const MIGRATIONS: &[fn(&mut dyn Storage) -> anyhow::Result<()>] = &[migrate_v1];
+
+/// Brings the stored settings up to `VERSION`, returns the version they
+/// were at. Runs at boot, before anything is read.
+pub fn migrate(storage: &mut dyn Storage) -> anyhow::Result<i64> {
+    let from = match storage.get(
+        VERSION_KEY,
+        Kind::Integer {
+            min: 0,
+            max: i64::MAX,
+        },
+    )? {
+        Some(Value::Integer(v)) => v,
+        _ => 0,
+    };
+    if from > VERSION {
+        log::warn!(
+            "Settings version {} is newer than {}, keeping them",
+            from,
+            VERSION
+        );
+        return Ok(from);
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(storage)?;
+        storage.set(VERSION_KEY, &Value::Integer(version as i64 + 1))?;
+        log::info!("Migrated settings to version {}", version + 1);
+    }
+    Ok(from)
+}
+
+/// Moves the single ssid/pass pair of older firmware into the saved networks
+/// and adds the trailing slash older firmware didn't enforce on the server
+/// URL.
+fn migrate_v1(storage: &mut dyn Storage) -> anyhow::Result<()> {
+    let text = |max_len| Kind::Text { max_len };
+    if let Some(Value::Text(ssid)) = storage.get("ssid", text(32))? {
+        let pass = match storage.get("pass", text(64))? {
+            Some(Value::Text(pass)) => pass,
+            _ => String::new(),
+        };
+        let mut networks = crate::networks::load(storage);
+        // a pair no network accepts is dropped rather than holding up the
+        // later migrations
+        match networks.add(crate::networks::KnownNetwork {
+            ssid: ssid.clone(),
+            pass,
+            ..Default::default()
+        }) {
+            Ok(()) => {
+                crate::networks::save(storage, &networks)?;
+                log::info!("Moved network {} into the saved networks", ssid);
+            }
+            Err(e) => log::warn!("Dropped saved network {}: {:?}", ssid, e),
+        }
+        storage.remove("ssid")?;
+        storage.remove("pass")?;
+    }
+
+    let server_url = def("server_url")?;
+    if let Some(url) = storage.get(server_url.key, server_url.kind)? {
+        match server_url.validate(url) {
+            Ok(url) => storage.set(server_url.key, &url)?,
+            Err(e) => log::warn!("Keeping the stored server URL: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_settings_write() {
+    let (setting, value) = Write::parse(br#"{"key":"server_url","value":"ws://echokit.dev/ws"}"#)
+        .unwrap()
+        .validate()
+        .unwrap();
+    assert_eq!(setting.key, "server_url");
+    assert_eq!(value, Value::Text("ws://echokit.dev/ws/".to_string()));
+
+    // the same write as msgpack
+    #[derive(Serialize)]
+    struct W<'a> {
+        key: &'a str,
+        value: &'a str,
+    }
+    let msgpack = rmp_serde::to_vec_named(&W {
+        key: "server_url",
+        value: "wss://a/",
+    })
+    .unwrap();
+    let (_, value) = Write::parse(&msgpack).unwrap().validate().unwrap();
+    assert_eq!(value, Value::Text("wss://a/".to_string()));
+
+    let invalid = [
+        r#"{"key":"nope","value":1}"#,
+        r#"{"key":"server_url","value":1}"#,
+        r#"{"key":"server_url","value":"http://a/"}"#,
+    ];
+    for w in invalid {
+        assert!(
+            Write::parse(w.as_bytes()).unwrap().validate().is_err(),
+            "{}",
+            w
+        );
+    }
+    let long = format!(
+        r#"{{"key":"server_url","value":"ws://{}"}}"#,
+        "a".repeat(200)
+    );
+    assert!(Write::parse(long.as_bytes()).unwrap().validate().is_err());
+
+    let volume = def("volume").unwrap();
+    assert!(volume.validate(Value::Integer(100)).is_ok());
+    assert!(volume.validate(Value::Integer(101)).is_err());
+    assert!(volume.validate(Value::Bool(true)).is_err());
+    let (_, value) = Write::parse(br#"{"key":"ble_remote","value":true}"#)
+        .unwrap()
+        .validate()
+        .unwrap();
+    assert_eq!(value, Value::Bool(true));
+
+    let schema = String::from_utf8(schema_json(|def| {
+        (def.key == "volume").then_some(Value::Integer(80))
+    }))
+    .unwrap();
+    assert_eq!(
+        schema,
+        r#"[{"key":"server_url","type":"text","max_len":127},"#.to_string()
+            + r#"{"key":"volume","type":"integer","min":0,"max":100,"default":"100","value":80},"#
+            + r#"{"key":"ble_remote","type":"bool","default":"off"},"#
+            + r#"{"key":"token","type":"text","max_len":255,"secret":true},"#
+            + r#"{"key":"language","type":"text","max_len":16}]"#
+    );
+
+    let language = def("language").unwrap();
+    for tag in ["en", "zh-CN", ""] {
+        assert!(language.parse_str(tag).is_ok(), "{}", tag);
+    }
+    for tag in ["en_US", "-en", "en-", "zh CN"] {
+        assert!(language.parse_str(tag).is_err(), "{}", tag);
+    }
+}
+
+#[test]
+fn test_settings_store() {
+    // every default parses
+    for def in SCHEMA {
+        if let Some(default) = def.default {
+            def.parse_str(default).unwrap();
+        }
+    }
+
+    // what older firmware left behind
+    let mut storage = MemStorage::default();
+    let text = |s: &str| Value::Text(s.to_string());
+    storage.set("ssid", &text("home")).unwrap();
+    storage.set("pass", &text("12345678")).unwrap();
+    storage
+        .set("server_url", &text("ws://echokit.dev/ws"))
+        .unwrap();
+
+    assert_eq!(migrate(&mut storage).unwrap(), 0);
+    assert_eq!(
+        storage.get(VERSION_KEY, Kind::Bool).unwrap(),
+        Some(Value::Integer(VERSION))
+    );
+    assert_eq!(storage.get("ssid", Kind::Bool).unwrap(), None);
+    let networks = crate::networks::load(&storage);
+    assert_eq!(networks.list()[0].ssid, "home");
+    assert_eq!(networks.list()[0].pass, "12345678");
+    // a second run has nothing to do
+    let migrated = storage.clone();
+    assert_eq!(migrate(&mut storage).unwrap(), VERSION);
+    assert_eq!(storage, migrated);
+
+    let settings = Settings::load(&storage);
+    assert_eq!(
+        settings,
+        Settings {
+            server_url: "ws://echokit.dev/ws/".to_string(),
+            volume: 100,
+            ble_remote: false,
+            token: String::new(),
+            language: String::new(),
+        }
+    );
+
+    // a stored value that no longer validates falls back to the default
+    storage.set("volume", &Value::Integer(300)).unwrap();
+    assert_eq!(
+        load(&storage, def("volume").unwrap()),
+        Some(Value::Integer(100))
+    );
+    assert!(save(&mut storage, def("volume").unwrap(), &Value::Integer(300)).is_err());
+    save(&mut storage, def("volume").unwrap(), &Value::Integer(30)).unwrap();
+
+    let storage = MemStorage::from_json(&storage.to_json()).unwrap();
+    assert_eq!(Settings::load(&storage).volume, 30);
+    assert_eq!(crate::networks::load(&storage).list().len(), 1);
+
+    // settings from newer firmware are left alone
+    let mut storage = MemStorage::default();
+    storage
+        .set(VERSION_KEY, &Value::Integer(VERSION + 1))
+        .unwrap();
+    storage.set("ssid", &text("home")).unwrap();
+    assert_eq!(migrate(&mut storage).unwrap(), VERSION + 1);
+    assert!(storage.get("ssid", Kind::Bool).unwrap().is_some());
+}
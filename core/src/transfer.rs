@@ -0,0 +1,355 @@
+use crate::protocol::{AssetKind, Checksum};
+
+pub const MAX_ASSET_SIZE: u32 = 1024 * 1024;
+/// Largest background GIF accepted over BLE, what the setup page allows.
+pub const MAX_GIF_SIZE: u32 = 500 * 1024;
+
+/// A single asset being received in chunks. `K` tells what it is, which is
+/// an asset kind except for uploads that aren't assets.
+///
+/// Chunks must arrive in order. Anything that doesn't continue at
+/// `received()` is dropped so the sender can rewind to that offset, which is
+/// also how a transfer resumes after a reconnect.
+pub struct Transfer<K = AssetKind> {
+    pub id: u32,
+    pub kind: K,
+    pub name: Option<String>,
+    size: u32,
+    checksum: Checksum,
+    data: Vec<u8>,
+}
+
+impl<K: Copy + PartialEq> Transfer<K> {
+    pub fn new(
+        id: u32,
+        kind: K,
+        name: Option<String>,
+        size: u32,
+        checksum: Checksum,
+    ) -> anyhow::Result<Self> {
+        if size == 0 || size > MAX_ASSET_SIZE {
+            anyhow::bail!("Invalid asset size {} (max {})", size, MAX_ASSET_SIZE);
+        }
+        Ok(Self {
+            id,
+            kind,
+            name,
+            size,
+            checksum,
+            // reserved by `Transfers::start`, once the previous one is gone
+            data: Vec::new(),
+        })
+    }
+
+    pub fn received(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn is_same(&self, other: &Transfer<K>) -> bool {
+        self.id == other.id
+            && self.kind == other.kind
+            && self.name == other.name
+            && self.size == other.size
+            && self.checksum == other.checksum
+    }
+
+    /// Returns `false` if the chunk is out of order and was ignored.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> anyhow::Result<bool> {
+        if offset != self.received() {
+            return Ok(false);
+        }
+        if self.data.len() + data.len() > self.size as usize {
+            anyhow::bail!("Chunk at {} overruns asset size {}", offset, self.size);
+        }
+        self.data.extend_from_slice(data);
+        Ok(true)
+    }
+
+    pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+        if self.received() != self.size {
+            anyhow::bail!("Incomplete asset: {}/{} bytes", self.received(), self.size);
+        }
+        if !verify(&self.checksum, &self.data) {
+            anyhow::bail!("Checksum mismatch");
+        }
+        Ok(self.data)
+    }
+}
+
+pub fn verify(checksum: &Checksum, data: &[u8]) -> bool {
+    match checksum {
+        Checksum::Crc32(crc) => crc32fast::hash(data) == *crc,
+        Checksum::Sha256(hash) => {
+            use sha2::Digest;
+            sha2::Sha256::digest(data).as_slice() == hash.as_slice()
+        }
+    }
+}
+
+/// Keeps at most one pending transfer, so a restarted transfer with the same
+/// parameters picks up where the previous one stopped.
+pub struct Transfers<K = AssetKind> {
+    current: Option<Transfer<K>>,
+}
+
+impl<K> Default for Transfers<K> {
+    fn default() -> Self {
+        Self { current: None }
+    }
+}
+
+impl<K: Copy + PartialEq> Transfers<K> {
+    /// Returns the offset the sender should continue from.
+    pub fn start(&mut self, mut transfer: Transfer<K>) -> u32 {
+        if let Some(t) = self.current.take() {
+            if t.is_same(&transfer) {
+                log::info!("Resuming transfer {} at {}", t.id, t.received());
+                let received = t.received();
+                self.current = Some(t);
+                return received;
+            }
+            log::warn!("Dropping pending transfer {}", t.id);
+        }
+        transfer.data.reserve_exact(transfer.size as usize);
+        self.current = Some(transfer);
+        0
+    }
+
+    /// Returns `Some(offset)` if the chunk was out of order and the sender
+    /// should rewind to `offset`.
+    pub fn chunk(&mut self, id: u32, offset: u32, data: &[u8]) -> anyhow::Result<Option<u32>> {
+        let t = self.get_mut(id)?;
+        if t.write(offset, data)? {
+            Ok(None)
+        } else {
+            Ok(Some(t.received()))
+        }
+    }
+
+    /// Returns the kind, name and data of the verified asset.
+    pub fn commit(&mut self, id: u32) -> anyhow::Result<(K, Option<String>, Vec<u8>)> {
+        self.get_mut(id)?;
+        let mut t = self.current.take().unwrap();
+        let (kind, name) = (t.kind, t.name.take());
+        Ok((kind, name, t.finish()?))
+    }
+
+    pub fn abort(&mut self, id: u32) {
+        if self.current.as_ref().is_some_and(|t| t.id == id) {
+            self.current = None;
+        }
+    }
+
+    /// `(id, received)` of the pending transfer, if any.
+    pub fn pending(&self) -> Option<(u32, u32)> {
+        self.current.as_ref().map(|t| (t.id, t.received()))
+    }
+
+    fn get_mut(&mut self, id: u32) -> anyhow::Result<&mut Transfer<K>> {
+        match &mut self.current {
+            Some(t) if t.id == id => Ok(t),
+            _ => anyhow::bail!("Unknown transfer {}", id),
+        }
+    }
+}
+
+/// Collects the `HelloChunk` and `BGChunk` messages, which come without a
+/// size. At most `MAX_ASSET_SIZE` bytes are kept: the chunk that goes past
+/// it drops the upload, and later chunks are ignored until the next start.
+#[derive(Default)]
+pub struct ChunkBuffer {
+    data: Vec<u8>,
+    overrun: bool,
+}
+
+impl ChunkBuffer {
+    pub fn start(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Fails only for the chunk that overruns the limit.
+    pub fn push(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.overrun {
+            return Ok(());
+        }
+        if self.data.len() + data.len() > MAX_ASSET_SIZE as usize {
+            self.start();
+            self.overrun = true;
+            anyhow::bail!("Asset is larger than {} bytes", MAX_ASSET_SIZE);
+        }
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Takes the data, leaving the buffer empty for the next upload.
+    pub fn finish(&mut self) -> anyhow::Result<Vec<u8>> {
+        let buffer = std::mem::take(self);
+        if buffer.overrun {
+            anyhow::bail!("Asset is larger than {} bytes", MAX_ASSET_SIZE);
+        }
+        Ok(buffer.data)
+    }
+}
+
+/// A command written to the BLE background characteristic. The first byte is
+/// the opcode, integers are little-endian:
+///
+/// - `0x01 size:u32 crc32:u32` begins (or resumes) an upload of at most the
+///   `max_size` given to [`UploadCommand::parse`]
+/// - `0x02 offset:u32 data..` writes a chunk
+/// - `0x03` commits the upload once all bytes are in
+/// - `0x04` aborts it
+#[derive(Debug, PartialEq)]
+pub enum UploadCommand<'a> {
+    Begin { size: u32, crc32: u32 },
+    Chunk { offset: u32, data: &'a [u8] },
+    Commit,
+    Abort,
+}
+
+impl<'a> UploadCommand<'a> {
+    pub fn parse(data: &'a [u8], max_size: u32) -> anyhow::Result<Self> {
+        let u32_at = |i: usize| {
+            data.get(i..i + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| anyhow::anyhow!("Upload command is too short"))
+        };
+        match data.first() {
+            Some(0x01) => {
+                let size = u32_at(1)?;
+                if size == 0 || size > max_size {
+                    anyhow::bail!("Invalid upload size {} (max {})", size, max_size);
+                }
+                Ok(Self::Begin {
+                    size,
+                    crc32: u32_at(5)?,
+                })
+            }
+            Some(0x02) => Ok(Self::Chunk {
+                offset: u32_at(1)?,
+                data: &data[5..],
+            }),
+            Some(0x03) => Ok(Self::Commit),
+            Some(0x04) => Ok(Self::Abort),
+            Some(op) => anyhow::bail!("Unknown upload opcode {:#04x}", op),
+            None => anyhow::bail!("Empty upload command"),
+        }
+    }
+}
+
+/// Notified as JSON in reply to upload commands, e.g.
+/// `{"status":"progress","received":16384}`. `progress` is also sent after an
+/// out-of-order chunk, the sender continues from `received`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UploadStatus {
+    Progress { received: u32 },
+    Done,
+    Aborted,
+    Error { message: String },
+}
+
+#[test]
+fn test_transfer_resume() {
+    let data = b"hello world".to_vec();
+    let checksum = Checksum::Crc32(crc32fast::hash(&data));
+
+    let new_transfer = || {
+        Transfer::new(
+            1,
+            AssetKind::Hello,
+            None,
+            data.len() as u32,
+            checksum.clone(),
+        )
+        .unwrap()
+    };
+
+    // nothing is reserved for a transfer that only resumes
+    assert_eq!(new_transfer().data.capacity(), 0);
+
+    let mut transfers = Transfers::default();
+    assert_eq!(transfers.start(new_transfer()), 0);
+    assert_eq!(transfers.chunk(1, 0, &data[..5]).unwrap(), None);
+    // out of order chunk asks for a rewind
+    assert_eq!(transfers.chunk(1, 8, &data[8..]).unwrap(), Some(5));
+
+    // the same transfer restarted after a reconnect resumes at 5
+    assert_eq!(transfers.start(new_transfer()), 5);
+    assert_eq!(transfers.chunk(1, 5, &data[5..]).unwrap(), None);
+
+    let (kind, name, asset) = transfers.commit(1).unwrap();
+    assert_eq!(kind, AssetKind::Hello);
+    assert_eq!(name, None);
+    assert_eq!(asset, data);
+    assert!(transfers.pending().is_none());
+}
+
+#[test]
+fn test_transfer_bad_checksum() {
+    let mut transfers = Transfers::default();
+    transfers.start(Transfer::new(2, AssetKind::Background, None, 3, Checksum::Crc32(0)).unwrap());
+    transfers.chunk(2, 0, &[1, 2, 3]).unwrap();
+    assert!(transfers.commit(2).is_err());
+    assert!(transfers.pending().is_none());
+}
+
+#[test]
+fn test_chunk_buffer() {
+    let mut buffer = ChunkBuffer::default();
+    buffer.push(b"GIF").unwrap();
+    buffer.push(b"89a").unwrap();
+    assert_eq!(buffer.finish().unwrap(), b"GIF89a");
+
+    let chunk = vec![0; 4096];
+    buffer.start();
+    for _ in 0..MAX_ASSET_SIZE as usize / chunk.len() {
+        buffer.push(&chunk).unwrap();
+    }
+    assert!(buffer.push(&[0]).is_err());
+    // dropped until the next start
+    assert!(buffer.push(&[0]).is_ok());
+    assert!(buffer.finish().is_err());
+    assert!(buffer.finish().unwrap().is_empty());
+}
+
+#[test]
+fn test_upload_command() {
+    let begin = [0x01, 0x10, 0x00, 0x00, 0x00, 0x78, 0x56, 0x34, 0x12];
+    assert_eq!(
+        UploadCommand::parse(&begin, MAX_GIF_SIZE).unwrap(),
+        UploadCommand::Begin {
+            size: 16,
+            crc32: 0x12345678
+        }
+    );
+    assert_eq!(
+        UploadCommand::parse(&[0x02, 0x00, 0x02, 0x00, 0x00, b'G', b'I'], MAX_GIF_SIZE).unwrap(),
+        UploadCommand::Chunk {
+            offset: 512,
+            data: b"GI"
+        }
+    );
+    assert_eq!(
+        UploadCommand::parse(&[0x03], MAX_GIF_SIZE).unwrap(),
+        UploadCommand::Commit
+    );
+    assert_eq!(
+        UploadCommand::parse(&[0x04], MAX_GIF_SIZE).unwrap(),
+        UploadCommand::Abort
+    );
+    // truncated header, unknown opcode and the old raw GIF writes
+    assert!(UploadCommand::parse(&begin[..6], MAX_GIF_SIZE).is_err());
+    assert!(UploadCommand::parse(&[0x02, 0x00], MAX_GIF_SIZE).is_err());
+    assert!(UploadCommand::parse(b"GIF89a", MAX_GIF_SIZE).is_err());
+    assert!(UploadCommand::parse(&[], MAX_GIF_SIZE).is_err());
+    // sizes past the limit are refused before anything is reserved
+    let mut too_big = begin;
+    too_big[1..5].copy_from_slice(&(MAX_GIF_SIZE + 1).to_le_bytes());
+    assert!(UploadCommand::parse(&too_big, MAX_GIF_SIZE).is_err());
+    assert!(UploadCommand::parse(&too_big, MAX_ASSET_SIZE).is_ok());
+    assert!(UploadCommand::parse(&[0x01, 0, 0, 0, 0, 0, 0, 0, 0], MAX_GIF_SIZE).is_err());
+
+    let status = serde_json::to_string(&UploadStatus::Progress { received: 5 }).unwrap();
+    assert_eq!(status, r#"{"status":"progress","received":5}"#);
+}
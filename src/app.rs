@@ -2,8 +2,13 @@ use tokio::sync::mpsc;
 use tokio_websockets::Message;
 
 use crate::{
+    assets::{self, SharedAssets},
     audio::{self, AudioData},
-    protocol::ServerEvent,
+    network::{WifiState, WifiStateRx},
+    ota::{self, EspFlashWriter, OtaState, Updater},
+    protocol::{AssetKind, ClientEvent, FirmwareImage, ServerEvent},
+    reset,
+    transfer::{ChunkBuffer, Transfer, Transfers},
     ws::Server,
 };
 
@@ -14,6 +19,8 @@ pub enum Event {
     MicAudioChunk(Vec<u8>),
     MicAudioEnd,
     WakeWordDetected(i32), // 唤醒词检测事件，包含唤醒词ID
+    ServerDisconnected,
+    Wifi(WifiState),
 }
 
 #[allow(dead_code)]
@@ -26,53 +33,82 @@ impl Event {
     pub const UNKNOWN: &'static str = "unknown";
     pub const K0: &'static str = "k0";
     pub const K0_: &'static str = "k0_";
+    /// Stop listening, recording or speaking, sent by the BLE remote.
+    pub const IDLE: &'static str = "idle";
 
     pub const K1: &'static str = "k1";
     pub const K2: &'static str = "k2";
 }
 
-async fn select_evt(evt_rx: &mut mpsc::Receiver<Event>, server: &mut Server) -> Option<Event> {
-    tokio::select! {
-        Some(evt) = evt_rx.recv() => {
-            match &evt {
-                Event::Event(_)=>{
-                    log::info!("Received event: {:?}", evt);
-                },
-                Event::MicAudioEnd=>{
-                    log::info!("Received MicAudioEnd");
-                },
-                Event::MicAudioChunk(data)=>{
-                    log::debug!("Received MicAudioChunk with {} bytes", data.len());
-                },
-                Event::ServerEvent(_)=>{
-                    log::info!("Received ServerEvent: {:?}", evt);
-                },
-                Event::WakeWordDetected(id)=>{
-                    log::info!("Received WakeWordDetected event with ID: {}", id);
-                },
-            }
-            Some(evt)
-        }
-        Ok(msg) = server.recv() => {
-            match msg {
-                Event::ServerEvent(ServerEvent::AudioChunk { .. })=>{
-                    log::info!("Received AudioChunk");
-                }
-                Event::ServerEvent(ServerEvent::HelloChunk { .. })=>{
-                    log::info!("Received HelloChunk");
-                }
-                Event::ServerEvent(ServerEvent::BGChunk { .. })=>{
-                    log::info!("Received BGChunk");
+async fn select_evt(
+    evt_rx: &mut mpsc::Receiver<Event>,
+    server: &mut Server,
+    wifi: &mut WifiStateRx,
+) -> Option<Event> {
+    loop {
+        tokio::select! {
+            Some(evt) = evt_rx.recv() => {
+                match &evt {
+                    Event::Event(_)=>{
+                        log::info!("Received event: {:?}", evt);
+                    },
+                    Event::MicAudioEnd=>{
+                        log::info!("Received MicAudioEnd");
+                    },
+                    Event::MicAudioChunk(data)=>{
+                        log::debug!("Received MicAudioChunk with {} bytes", data.len());
+                    },
+                    Event::ServerEvent(_)=>{
+                        log::info!("Received ServerEvent: {:?}", evt);
+                    },
+                    Event::WakeWordDetected(id)=>{
+                        log::info!("Received WakeWordDetected event with ID: {}", id);
+                    },
+                    Event::ServerDisconnected | Event::Wifi(_)=>{
+                        log::info!("Received {:?}", evt);
+                    },
                 }
-                _=> {
-                    log::info!("Received message: {:?}", msg);
+                return Some(evt);
+            }
+            r = server.recv() => {
+                let msg = match r {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        // one bad frame doesn't end the session
+                        log::error!("Invalid message from the server: {:?}", e);
+                        continue;
+                    }
+                };
+                match msg {
+                    Event::ServerEvent(ServerEvent::AudioChunk { .. })=>{
+                        log::info!("Received AudioChunk");
+                    }
+                    Event::ServerEvent(ServerEvent::HelloChunk { .. })=>{
+                        log::info!("Received HelloChunk");
+                    }
+                    Event::ServerEvent(ServerEvent::BGChunk { .. })=>{
+                        log::info!("Received BGChunk");
+                    }
+                    Event::ServerEvent(ServerEvent::TransferChunk { id, offset, .. })=>{
+                        log::info!("Received TransferChunk {} at {}", id, offset);
+                    }
+                    Event::ServerEvent(ServerEvent::FirmwareChunk { offset, .. })=>{
+                        log::info!("Received FirmwareChunk at {}", offset);
+                    }
+                    _=> {
+                        log::info!("Received message: {:?}", msg);
+                    }
                 }
+                return Some(msg);
+            }
+            Ok(()) = wifi.changed() => {
+                let state = *wifi.borrow_and_update();
+                return Some(Event::Wifi(state));
+            }
+            else => {
+                log::info!("No events");
+                return None;
             }
-            Some(msg)
-        }
-        else => {
-            log::info!("No events");
-            None
         }
     }
 }
@@ -120,8 +156,11 @@ pub async fn main_work<'d>(
     mut server: Server,
     player_tx: audio::PlayerTx,
     mut evt_rx: mpsc::Receiver<Event>,
+    mut wifi: WifiStateRx,
+    assets: SharedAssets,
     backgroud_buffer: Option<&'d [u8]>,
     afe_handle: std::sync::Arc<audio::AFE>, // 添加AFE句柄参数
+    remote: Option<std::sync::Arc<crate::remote::Remote>>,
 ) -> anyhow::Result<()> {
     #[derive(PartialEq, Eq)]
     enum State {
@@ -132,12 +171,27 @@ pub async fn main_work<'d>(
         Idle,
     }
 
+    impl State {
+        fn name(&self) -> &'static str {
+            match self {
+                State::Listening => "listening",
+                State::Recording => "recording",
+                State::Wait => "wait",
+                State::Speaking => "speaking",
+                State::Idle => "idle",
+            }
+        }
+    }
+
     let mut gui = crate::ui::UI::new(backgroud_buffer)?;
 
     gui.state = "Idle".to_string();
     gui.display_flush().unwrap();
 
-    let mut new_gui_bg = vec![];
+    let mut new_gui_bg = ChunkBuffer::default();
+    let mut new_hello = ChunkBuffer::default();
+    let mut transfers = Transfers::default();
+    let mut firmware: Option<Updater<EspFlashWriter>> = None;
 
     let mut state = State::Idle;
     // 初始状态为idle，设置AFE为idle状态
@@ -150,9 +204,25 @@ pub async fn main_work<'d>(
     let mut metrics = DownloadMetrics::new();
     let mut need_compute = true;
     let mut speed = 0.8;
+    // when the server asked for a factory reset, see `reset::ACTION`
+    let mut reset_requested: Option<std::time::Instant> = None;
+    let reset_pending = |requested: &Option<std::time::Instant>| {
+        requested.is_some_and(|t| t.elapsed() < reset::CONFIRM_TIMEOUT)
+    };
 
-    while let Some(evt) = select_evt(&mut evt_rx, &mut server).await {
+    while let Some(evt) = select_evt(&mut evt_rx, &mut server, &mut wifi).await {
         match evt {
+            Event::Event(Event::K0_) if reset_pending(&reset_requested) => {
+                log::warn!("Factory reset confirmed on the device");
+                reset::factory_reset(&assets);
+            }
+            Event::Event(Event::K0) if reset_pending(&reset_requested) => {
+                log::info!("Factory reset cancelled on the device");
+                reset_requested = None;
+                gui.state = "Factory reset cancelled".to_string();
+                gui.text = String::new();
+                gui.display_flush().unwrap();
+            }
             Event::Event(Event::GAIA | Event::K0) => {
                 log::info!("Received event: gaia");
                 // gui.state = "gaia".to_string();
@@ -177,10 +247,10 @@ pub async fn main_work<'d>(
                     gui.state = "Listening...".to_string();
                     gui.display_flush().unwrap();
                 }
-            },
+            }
             Event::WakeWordDetected(id) => {
                 log::info!("Received wake word with ID: {}", id);
-                
+
                 if state == State::Idle {
                     // 在idle状态下检测到唤醒词("hi esp", ID=1)
                     if id == 1 {
@@ -205,9 +275,9 @@ pub async fn main_work<'d>(
                         state = State::Idle;
                         afe_handle.set_idle(); // 设置为空闲状态
                         gui.state = "Idle".to_string();
-                    gui.display_flush().unwrap();
+                        gui.display_flush().unwrap();
+                    }
                 }
-            },        }
             }
             Event::Event(Event::K0_) => {
                 if state == State::Idle || state == State::Listening {
@@ -220,6 +290,20 @@ pub async fn main_work<'d>(
                     log::warn!("Received K0_ while not idle");
                 }
             }
+            Event::Event(Event::IDLE) => {
+                if state == State::Speaking {
+                    let (tx, rx) = tokio::sync::oneshot::channel();
+                    if player_tx.send(AudioData::End(tx)).is_ok() {
+                        let _ = rx.await;
+                    }
+                }
+                state = State::Idle;
+                afe_handle.set_idle();
+                audio_buffer.clear();
+                submit_audio = 0.0;
+                gui.state = "Idle".to_string();
+                gui.display_flush().unwrap();
+            }
             Event::Event(Event::RESET | Event::K2) => {}
             Event::Event(Event::YES | Event::K1) => {}
             Event::Event(Event::NO) => {}
@@ -260,10 +344,24 @@ pub async fn main_work<'d>(
             }
             Event::ServerEvent(ServerEvent::ASR { text }) => {
                 log::info!("Received ASR: {:?}", text);
+                if let Some(remote) = &remote {
+                    remote.set_transcript(text.trim());
+                }
                 gui.state = "ASR".to_string();
                 gui.text = text.trim().to_string();
                 gui.display_flush().unwrap();
             }
+            Event::ServerEvent(ServerEvent::Action { action }) if action == reset::ACTION => {
+                log::warn!("Factory reset requested by the server, waiting for K0");
+                reset_requested = Some(std::time::Instant::now());
+                gui.state = "Factory reset?".to_string();
+                gui.text = format!(
+                    "The server asked to erase all settings and assets.\n\
+                     Hold K0 within {} seconds to confirm, press it to cancel.",
+                    reset::CONFIRM_TIMEOUT.as_secs()
+                );
+                gui.display_flush().unwrap();
+            }
             Event::ServerEvent(ServerEvent::Action { action }) => {
                 log::info!("Received action");
                 gui.state = format!("Action: {}", action);
@@ -339,24 +437,23 @@ pub async fn main_work<'d>(
                 gui.display_flush().unwrap();
             }
             Event::ServerEvent(ServerEvent::HelloStart) => {
-                if let Err(_) = player_tx.send(AudioData::SetHelloStart) {
-                    log::error!("Error sending hello start");
-                    gui.state = "Error on hello start".to_string();
-                    gui.display_flush().unwrap();
-                }
+                new_hello.start();
             }
             Event::ServerEvent(ServerEvent::HelloChunk { data }) => {
                 log::info!("Received hello chunk");
-                if let Err(_) = player_tx.send(AudioData::SetHelloChunk(data.to_vec())) {
-                    log::error!("Error sending hello chunk");
+                if let Err(e) = new_hello.push(&data) {
+                    log::error!("Dropping hello: {:?}", e);
                     gui.state = "Error on hello chunk".to_string();
                     gui.display_flush().unwrap();
                 }
             }
             Event::ServerEvent(ServerEvent::HelloEnd) => {
                 log::info!("Received hello end");
-                if let Err(_) = player_tx.send(AudioData::SetHelloEnd) {
-                    log::error!("Error sending hello end");
+                let r = new_hello.finish().and_then(|data| {
+                    set_hello(&player_tx, &assets, assets::DEFAULT_HELLO, data)
+                });
+                if let Err(e) = r {
+                    log::error!("Failed to set hello: {:?}", e);
                     gui.state = "Error on hello end".to_string();
                     gui.display_flush().unwrap();
                 } else {
@@ -364,35 +461,328 @@ pub async fn main_work<'d>(
                     gui.display_flush().unwrap();
                 }
             }
+            Event::ServerEvent(ServerEvent::HelloReset) => {
+                log::info!("Received hello reset");
+                let r = assets.lock().unwrap().set_active(AssetKind::Hello, None);
+                if let Err(e) = r {
+                    log::error!("Error resetting hello: {:?}", e);
+                }
+                if player_tx
+                    .send(AudioData::SetHello(audio::WAKE_WAV.to_vec()))
+                    .is_err()
+                {
+                    log::error!("Error sending hello reset");
+                }
+                gui.state = "Hello reset".to_string();
+                gui.display_flush().unwrap();
+            }
             Event::ServerEvent(ServerEvent::BGStart) => {
-                new_gui_bg = vec![];
+                new_gui_bg.start();
             }
             Event::ServerEvent(ServerEvent::BGChunk { data }) => {
                 log::info!("Received background chunk");
-                new_gui_bg.extend(data);
+                if let Err(e) = new_gui_bg.push(&data) {
+                    log::error!("Dropping background: {:?}", e);
+                    gui.state = "Error on background data".to_string();
+                    gui.display_flush().unwrap();
+                }
             }
             Event::ServerEvent(ServerEvent::BGEnd) => {
                 log::info!("Received background end");
-                if !new_gui_bg.is_empty() {
-                    let gui_ = crate::ui::UI::new(Some(&new_gui_bg));
-                    new_gui_bg.clear();
-                    match gui_ {
-                        Ok(new_gui) => {
-                            gui = new_gui;
-                            gui.state = "Background data loaded".to_string();
-                            gui.display_flush().unwrap();
+                match new_gui_bg.finish() {
+                    Ok(data) if !data.is_empty() => {
+                        let name = assets::DEFAULT_BACKGROUND;
+                        let _ = set_background(&mut gui, &assets, name, &data);
+                    }
+                    Ok(_) => log::warn!("Received empty background data"),
+                    Err(e) => log::error!("Dropped background: {:?}", e),
+                }
+            }
+            Event::ServerEvent(ServerEvent::TransferStart {
+                id,
+                kind,
+                name,
+                size,
+                checksum,
+            }) => {
+                log::info!("Received transfer start {} {:?} {} bytes", id, kind, size);
+                match Transfer::new(id, kind, name, size, checksum) {
+                    Ok(transfer) => {
+                        let offset = transfers.start(transfer);
+                        server
+                            .send_event(&ClientEvent::TransferAck { id, offset })
+                            .await?;
+                    }
+                    Err(e) => {
+                        log::error!("Error starting transfer {}: {:?}", id, e);
+                        server
+                            .send_event(&ClientEvent::TransferError {
+                                id,
+                                error: e.to_string(),
+                            })
+                            .await?;
+                    }
+                }
+            }
+            Event::ServerEvent(ServerEvent::TransferChunk { id, offset, data }) => {
+                match transfers.chunk(id, offset, &data) {
+                    Ok(None) => {}
+                    Ok(Some(offset)) => {
+                        log::warn!(
+                            "Out of order chunk for transfer {}, rewind to {}",
+                            id,
+                            offset
+                        );
+                        server
+                            .send_event(&ClientEvent::TransferAck { id, offset })
+                            .await?;
+                    }
+                    Err(e) => {
+                        log::error!("Error on transfer {} chunk: {:?}", id, e);
+                        transfers.abort(id);
+                        server
+                            .send_event(&ClientEvent::TransferError {
+                                id,
+                                error: e.to_string(),
+                            })
+                            .await?;
+                    }
+                }
+            }
+            Event::ServerEvent(ServerEvent::TransferCommit { id }) => {
+                log::info!("Received transfer commit {}", id);
+                let r = match transfers.commit(id) {
+                    Ok((AssetKind::Hello, name, data)) => {
+                        let name = name.as_deref().unwrap_or(assets::DEFAULT_HELLO);
+                        set_hello(&player_tx, &assets, name, data)
+                    }
+                    Ok((AssetKind::Background, name, data)) => {
+                        let name = name.as_deref().unwrap_or(assets::DEFAULT_BACKGROUND);
+                        set_background(&mut gui, &assets, name, &data)
+                    }
+                    Ok((AssetKind::Sound, Some(name), data)) => {
+                        assets.lock().unwrap().put(&name, AssetKind::Sound, &data)
+                    }
+                    Ok((AssetKind::Sound, None, _)) => Err(anyhow::anyhow!("Sound needs a name")),
+                    Err(e) => Err(e),
+                };
+                let evt = match r {
+                    Ok(()) => ClientEvent::TransferDone { id },
+                    Err(e) => {
+                        log::error!("Error on transfer {} commit: {:?}", id, e);
+                        gui.state = "Error on asset transfer".to_string();
+                        gui.display_flush().unwrap();
+                        ClientEvent::TransferError {
+                            id,
+                            error: e.to_string(),
                         }
-                        Err(e) => {
-                            log::error!("Error creating GUI from background data: {:?}", e);
-                            gui.state = "Error on background data".to_string();
-                            gui.display_flush().unwrap();
+                    }
+                };
+                server.send_event(&evt).await?;
+            }
+            Event::ServerEvent(ServerEvent::TransferAbort { id }) => {
+                log::info!("Received transfer abort {}", id);
+                transfers.abort(id);
+            }
+            Event::ServerEvent(ServerEvent::SelectAsset { kind, name }) => {
+                log::info!("Received select asset {:?} {:?}", kind, name);
+                if let Err(e) = select_asset(&mut gui, &player_tx, &assets, kind, name.as_deref()) {
+                    log::error!("Error selecting asset: {:?}", e);
+                    gui.state = "Error on select asset".to_string();
+                    gui.text = e.to_string();
+                    gui.display_flush().unwrap();
+                }
+            }
+            Event::ServerEvent(ServerEvent::FirmwareUpdate { url, image }) => {
+                log::info!("Received firmware update {} from {}", image.version, url);
+                let version = image.version.clone();
+                let readiness = ota::Readiness {
+                    busy: state != State::Idle,
+                    transferring: firmware.is_some() || transfers.pending().is_some(),
+                    brownout: ota::brownout_reset(),
+                };
+                if let Err(e) = ota::check_update(&image, readiness) {
+                    log::warn!("Firmware update rejected: {:?}", e);
+                    server
+                        .send_event(&ClientEvent::FirmwareReject {
+                            version,
+                            reason: e.to_string(),
+                        })
+                        .await?;
+                    continue;
+                }
+                afe_handle.set_idle();
+                // the loop waits for the download, nothing else can start
+                match firmware_update(&mut gui, url, image).await {
+                    Ok(()) => unsafe { esp_idf_svc::sys::esp_restart() },
+                    Err(e) => {
+                        firmware_failed(&mut gui, &e);
+                        server
+                            .send_event(&ClientEvent::FirmwareResult {
+                                version,
+                                error: Some(e.to_string()),
+                            })
+                            .await?;
+                    }
+                }
+            }
+            Event::ServerEvent(ServerEvent::FirmwareOffer { image }) => {
+                log::info!(
+                    "Received firmware offer {} ({} bytes)",
+                    image.version,
+                    image.size
+                );
+                let version = image.version.clone();
+                let readiness = ota::Readiness {
+                    busy: state != State::Idle,
+                    transferring: firmware.is_some() || transfers.pending().is_some(),
+                    brownout: ota::brownout_reset(),
+                };
+                let r = ota::accept_offer(image, readiness);
+                let evt = match r {
+                    Ok(updater) => {
+                        firmware = Some(updater);
+                        afe_handle.set_idle();
+                        gui.state = format!("Updating firmware to {}", version);
+                        gui.text = "Receiving...".to_string();
+                        gui.progress = Some(0.0);
+                        gui.display_flush().unwrap();
+                        ClientEvent::FirmwareAccept { version }
+                    }
+                    Err(e) => {
+                        log::warn!("Firmware offer rejected: {:?}", e);
+                        ClientEvent::FirmwareReject {
+                            version,
+                            reason: e.to_string(),
                         }
                     }
-                } else {
-                    log::warn!("Received empty background data");
+                };
+                server.send_event(&evt).await?;
+            }
+            Event::ServerEvent(ServerEvent::FirmwareChunk { offset, data }) => {
+                let Some(updater) = firmware.as_mut() else {
+                    log::warn!("Firmware chunk without an accepted offer");
+                    continue;
+                };
+                let written = updater.written() as u32;
+                if offset != written {
+                    log::warn!("Firmware chunk at {}, expected {}", offset, written);
+                    server
+                        .send_event(&ClientEvent::FirmwareProgress { offset: written })
+                        .await?;
+                    continue;
+                }
+                if let Err(e) = updater.write(&data) {
+                    let version = updater.version().to_string();
+                    firmware = None;
+                    firmware_failed(&mut gui, &e);
+                    server
+                        .send_event(&ClientEvent::FirmwareResult {
+                            version,
+                            error: Some(e.to_string()),
+                        })
+                        .await?;
+                    continue;
+                }
+                let written = updater.written();
+                if written / (64 * 1024) != (written - data.len()) / (64 * 1024) {
+                    if let OtaState::Downloading { total, .. } = *updater.state() {
+                        gui.progress = Some(written as f32 / total as f32);
+                        gui.text = format!("{} KB / {} KB", written / 1024, total / 1024);
+                        gui.display_flush().unwrap();
+                    }
+                    server
+                        .send_event(&ClientEvent::FirmwareProgress {
+                            offset: written as u32,
+                        })
+                        .await?;
+                }
+            }
+            Event::ServerEvent(ServerEvent::FirmwareEnd) => {
+                let Some(mut updater) = firmware.take() else {
+                    log::warn!("Firmware end without an accepted offer");
+                    continue;
+                };
+                gui.text = "Verifying...".to_string();
+                gui.display_flush().unwrap();
+                let version = updater.version().to_string();
+                match updater.finish() {
+                    Ok(()) => {
+                        server
+                            .send_event(&ClientEvent::FirmwareResult {
+                                version,
+                                error: None,
+                            })
+                            .await?;
+                        gui.text = "Update ready, restarting...".to_string();
+                        gui.display_flush().unwrap();
+                        unsafe { esp_idf_svc::sys::esp_restart() }
+                    }
+                    Err(e) => {
+                        firmware_failed(&mut gui, &e);
+                        server
+                            .send_event(&ClientEvent::FirmwareResult {
+                                version,
+                                error: Some(e.to_string()),
+                            })
+                            .await?;
+                    }
+                }
+            }
+            Event::ServerEvent(ServerEvent::FirmwareAbort) => {
+                if let Some(mut updater) = firmware.take() {
+                    log::info!("Firmware update {} aborted", updater.version());
+                    updater.abort();
+                    gui.progress = None;
+                    gui.state = "Idle".to_string();
+                    gui.text.clear();
+                    gui.display_flush().unwrap();
                 }
             }
             Event::ServerEvent(ServerEvent::StartVideo | ServerEvent::EndVideo) => {}
+            Event::Wifi(WifiState::Connected) => {
+                log::info!("Wifi connected");
+            }
+            Event::Wifi(wifi_state) => {
+                log::warn!("Wifi {:?}", wifi_state);
+                // The socket can't survive this, don't wait for TCP to notice.
+                server.disconnect();
+            }
+            Event::ServerDisconnected => {
+                if state == State::Speaking {
+                    // finish what was already queued, the rest isn't coming
+                    let (tx, rx) = tokio::sync::oneshot::channel();
+                    if player_tx.send(AudioData::End(tx)).is_ok() {
+                        let _ = rx.await;
+                    }
+                }
+                state = State::Idle;
+                afe_handle.set_idle();
+                audio_buffer.clear();
+                submit_audio = 0.0;
+                if let Some(mut updater) = firmware.take() {
+                    updater.abort();
+                    gui.progress = None;
+                }
+
+                reconnect(&mut server, &mut wifi, &mut gui).await;
+
+                // Let the server resume an asset transfer that was cut off.
+                if let Some((id, offset)) = transfers.pending() {
+                    server
+                        .send_event(&ClientEvent::TransferAck { id, offset })
+                        .await?;
+                }
+                gui.state = "Idle".to_string();
+                gui.text.clear();
+                gui.display_flush().unwrap();
+            }
+        }
+
+        // Arms that `continue` above don't change the state.
+        crate::network::pause_roaming(state != State::Idle);
+        if let Some(remote) = &remote {
+            remote.set_state(state.name());
         }
     }
 
@@ -400,3 +790,182 @@ pub async fn main_work<'d>(
 
     Ok(())
 }
+
+/// Reconnects to the server with backoff, waiting for Wi-Fi when it is down.
+async fn reconnect(server: &mut Server, wifi: &mut WifiStateRx, gui: &mut crate::ui::UI) {
+    let mut backoff = std::time::Duration::from_secs(1);
+    loop {
+        if *wifi.borrow() != WifiState::Connected {
+            gui.state = "Wifi disconnected".to_string();
+            gui.text = "Reconnecting...".to_string();
+            gui.display_flush().unwrap();
+            if wifi.wait_for(|s| *s == WifiState::Connected).await.is_err() {
+                log::error!("Wifi supervisor is gone");
+                std::future::pending::<()>().await;
+            }
+        }
+
+        gui.state = "Connecting to server...".to_string();
+        gui.text.clear();
+        gui.display_flush().unwrap();
+        match server.reconnect().await {
+            Ok(()) => {
+                log::info!("Reconnected to server");
+                return;
+            }
+            Err(e) => {
+                log::warn!("Failed to reconnect to server: {:?}", e);
+                gui.state = "Server unreachable".to_string();
+                gui.text = format!("Retrying in {}s", backoff.as_secs());
+                gui.display_flush().unwrap();
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(std::time::Duration::from_secs(30));
+    }
+}
+
+/// Stores the clip as the active hello and swaps it into the player.
+///
+/// A clip that can't be stored is still swapped in until the next restart,
+/// but the storage error is returned so the transfer is reported as failed.
+fn set_hello(
+    player_tx: &audio::PlayerTx,
+    assets: &SharedAssets,
+    name: &str,
+    data: Vec<u8>,
+) -> anyhow::Result<()> {
+    if data.is_empty() {
+        log::warn!("Received empty hello data");
+        anyhow::bail!("Empty hello data");
+    }
+    let saved = assets::save_hello(&mut *assets.lock().unwrap(), name, &data);
+    if let Err(e) = &saved {
+        log::error!("Failed to save hello: {:?}", e);
+    }
+    player_tx.send(AudioData::SetHello(data)).map_err(|_| {
+        log::error!("Error sending hello");
+        anyhow::anyhow!("Error sending hello")
+    })?;
+    saved
+}
+
+/// Stores the GIF as the boot background and applies it to the live UI.
+///
+/// A GIF that can't be parsed is rejected. One that is valid but doesn't fit
+/// in storage is still shown, with the storage error on screen.
+fn set_background(
+    gui: &mut crate::ui::UI,
+    assets: &SharedAssets,
+    name: &str,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    if let Err(e) = crate::ui::check_gif(data) {
+        log::error!("Invalid background data: {:?}", e);
+        gui.state = "Error on background data".to_string();
+        gui.text = e.to_string();
+        gui.display_flush().unwrap();
+        return Err(e);
+    }
+
+    let saved = assets::save_background(&mut *assets.lock().unwrap(), name, data);
+
+    match crate::ui::UI::new(Some(data)) {
+        Ok(new_gui) => {
+            *gui = new_gui;
+            if let Err(e) = &saved {
+                log::error!("Failed to save background GIF: {:?}", e);
+                gui.state = "Background not saved".to_string();
+                gui.text = e.to_string();
+            } else {
+                gui.state = "Background data loaded".to_string();
+            }
+            gui.display_flush().unwrap();
+            saved
+        }
+        Err(e) => {
+            log::error!("Error creating GUI from background data: {:?}", e);
+            gui.state = "Error on background data".to_string();
+            gui.display_flush().unwrap();
+            Err(e)
+        }
+    }
+}
+
+fn select_asset(
+    gui: &mut crate::ui::UI,
+    player_tx: &audio::PlayerTx,
+    assets: &SharedAssets,
+    kind: AssetKind,
+    name: Option<&str>,
+) -> anyhow::Result<()> {
+    let data = {
+        let mut assets = assets.lock().unwrap();
+        assets.set_active(kind, name)?;
+        assets.active(kind)
+    };
+    match kind {
+        AssetKind::Hello => {
+            let data = data.unwrap_or_else(|| audio::WAKE_WAV.to_vec());
+            player_tx
+                .send(AudioData::SetHello(data))
+                .map_err(|_| anyhow::anyhow!("Error sending hello"))?;
+        }
+        AssetKind::Background => {
+            *gui = crate::ui::UI::new(data.as_deref())?;
+            gui.state = "Background changed".to_string();
+            gui.display_flush().unwrap();
+        }
+        AssetKind::Sound => anyhow::bail!("Sounds have no active selection"),
+    }
+    Ok(())
+}
+
+fn firmware_failed(gui: &mut crate::ui::UI, e: &anyhow::Error) {
+    log::error!("Firmware update failed: {:?}", e);
+    gui.progress = None;
+    gui.state = "Firmware update failed".to_string();
+    gui.text = e.to_string();
+    gui.display_flush().unwrap();
+}
+
+/// Runs `ota::download` on its own thread and shows its progress.
+async fn firmware_update(
+    gui: &mut crate::ui::UI,
+    url: String,
+    image: FirmwareImage,
+) -> anyhow::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    gui.state = format!("Updating firmware to {}", image.version);
+    gui.text = "Downloading...".to_string();
+    gui.progress = Some(0.0);
+    gui.display_flush().unwrap();
+
+    let handle = std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            crate::ota::download(&url, image, |state| {
+                let _ = tx.send(state.clone());
+            })
+        })?;
+
+    while let Some(ota_state) = rx.recv().await {
+        match ota_state {
+            OtaState::Downloading { written, total } => {
+                gui.progress = Some(written as f32 / total as f32);
+                gui.text = format!("{} KB / {} KB", written / 1024, total / 1024);
+            }
+            OtaState::Verifying => gui.text = "Verifying...".to_string(),
+            OtaState::Ready => gui.text = "Update ready, restarting...".to_string(),
+            OtaState::Failed(e) => gui.text = e,
+        }
+        gui.display_flush().unwrap();
+    }
+
+    gui.progress = None;
+    handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("Firmware update thread panicked"))?
+}
@@ -0,0 +1,52 @@
+pub use echokit_core::assets::*;
+
+use crate::protocol::AssetKind;
+
+pub type SharedAssets = std::sync::Arc<std::sync::Mutex<AssetStore<FsStorage>>>;
+
+pub const MOUNT_POINT: &str = "/assets";
+pub const DEFAULT_HELLO: &str = "hello.wav";
+pub const DEFAULT_BACKGROUND: &str = "background.gif";
+
+pub fn mount() -> anyhow::Result<AssetStore<FsStorage>> {
+    use esp_idf_svc::sys::*;
+
+    let conf = esp_vfs_spiffs_conf_t {
+        base_path: c"/assets".as_ptr(),
+        partition_label: c"assets".as_ptr(),
+        max_files: 4,
+        format_if_mount_failed: true,
+    };
+    esp!(unsafe { esp_vfs_spiffs_register(&conf) })?;
+
+    let (mut total, mut used) = (0, 0);
+    esp!(unsafe { esp_spiffs_info(conf.partition_label, &mut total, &mut used) })?;
+    log::info!(
+        "Asset partition: {} KB used of {} KB",
+        used / 1024,
+        total / 1024
+    );
+
+    Ok(AssetStore::new(FsStorage::new(MOUNT_POINT)))
+}
+
+/// Stores a GIF and makes it the boot background.
+pub fn save_background<S: Storage>(
+    store: &mut AssetStore<S>,
+    name: &str,
+    gif: &[u8],
+) -> anyhow::Result<()> {
+    crate::ui::check_gif(gif)?;
+    store.put(name, AssetKind::Background, gif)?;
+    store.set_active(AssetKind::Background, Some(name))
+}
+
+/// Stores a clip and makes it the hello sound.
+pub fn save_hello<S: Storage>(
+    store: &mut AssetStore<S>,
+    name: &str,
+    wav: &[u8],
+) -> anyhow::Result<()> {
+    store.put(name, AssetKind::Hello, wav)?;
+    store.set_active(AssetKind::Hello, Some(name))
+}
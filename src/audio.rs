@@ -15,6 +15,40 @@ enum WakeWordState {
 const SAMPLE_RATE: u32 = 16000;
 const PORT_TICK_PERIOD_MS: u32 = 1000 / esp_idf_svc::sys::configTICK_RATE_HZ;
 
+// Output volume in percent, applied in software so it works on every board.
+static VOLUME: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(100);
+static MUTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub fn set_volume(volume: u8) {
+    VOLUME.store(volume.min(100), Ordering::Relaxed);
+}
+
+pub fn volume() -> u8 {
+    VOLUME.load(Ordering::Relaxed)
+}
+
+pub fn set_muted(muted: bool) {
+    MUTED.store(muted, Ordering::Relaxed);
+}
+
+pub fn is_muted() -> bool {
+    MUTED.load(Ordering::Relaxed)
+}
+
+/// Scales 16-bit PCM to the current volume.
+fn with_volume(data: &[u8]) -> std::borrow::Cow<[u8]> {
+    let volume = if is_muted() { 0 } else { volume() as i32 };
+    if volume >= 100 {
+        return std::borrow::Cow::Borrowed(data);
+    }
+    let mut data = data.to_vec();
+    for sample in data.chunks_exact_mut(2) {
+        let v = i16::from_le_bytes([sample[0], sample[1]]) as i32 * volume / 100;
+        sample.copy_from_slice(&(v as i16).to_le_bytes());
+    }
+    std::borrow::Cow::Owned(data)
+}
+
 unsafe fn afe_init() -> (
     *mut esp_sr::esp_afe_sr_iface_t,
     *mut esp_sr::esp_afe_sr_data_t,
@@ -182,9 +216,7 @@ pub static WAKE_WAV: &[u8] = include_bytes!("../assets/hello_beep.wav");
 
 pub enum AudioData {
     Hello(tokio::sync::oneshot::Sender<()>),
-    SetHelloStart,
-    SetHelloChunk(Vec<u8>),
-    SetHelloEnd,
+    SetHello(Vec<u8>),
     Start,
     Chunk(Vec<u8>),
     End(tokio::sync::oneshot::Sender<()>),
@@ -205,10 +237,24 @@ pub async fn i2s_task_(
     dout: AnyIOPin,
     (tx, rx): (MicTx, PlayerRx),
     afe_handle: Arc<AFE>, // 添加AFE实例参数
+    hello_audio: Vec<u8>,
 ) {
     let afe_handle_ = afe_handle.clone();
     let afe_r = std::thread::spawn(|| afe_worker(afe_handle_, tx));
-    let r = i2s_player_(i2s, ws, sck, din, i2s1, bclk, lrclk, dout, afe_handle, rx).await;
+    let r = i2s_player_(
+        i2s,
+        ws,
+        sck,
+        din,
+        i2s1,
+        bclk,
+        lrclk,
+        dout,
+        afe_handle,
+        rx,
+        hello_audio,
+    )
+    .await;
     if let Err(e) = r {
         log::error!("Error: {}", e);
     } else {
@@ -233,6 +279,7 @@ async fn i2s_player_(
     dout: AnyIOPin,
     afe_handle: Arc<AFE>,
     mut rx: PlayerRx,
+    mut hello_audio: Vec<u8>,
 ) -> anyhow::Result<()> {
     let i2s_config = config::StdConfig::new(
         config::Config::default().auto_clear(true),
@@ -256,9 +303,7 @@ async fn i2s_player_(
     let mut buf = [0u8; 2 * 160];
     let mut speaking = false;
 
-    let mut hello_audio = WAKE_WAV.to_vec();
-
-    tx_driver.write_all(&hello_audio, 100 / PORT_TICK_PERIOD_MS)?;
+    tx_driver.write_all(&with_volume(&hello_audio), 100 / PORT_TICK_PERIOD_MS)?;
     log::info!("Playing hello audio, waiting for response...");
 
     loop {
@@ -283,24 +328,17 @@ async fn i2s_player_(
                 AudioData::Hello(tx) => {
                     log::info!("Received hello");
                     tx_driver
-                        .write_all_async(&hello_audio)
+                        .write_all_async(&with_volume(&hello_audio))
                         .await
                         .map_err(|e| anyhow::anyhow!("Error play hello: {:?}", e))?;
                     let _ = tx.send(());
                     speaking = false;
                 }
-                AudioData::SetHelloStart => {
-                    log::info!("Received set hello start");
-                    hello_audio.clear();
-                }
-                AudioData::SetHelloChunk(data) => {
-                    log::info!("Received set hello chunk");
-                    hello_audio.extend(data);
-                }
-                AudioData::SetHelloEnd => {
-                    log::info!("Received set hello end");
+                AudioData::SetHello(data) => {
+                    log::info!("Received set hello");
+                    hello_audio = data;
                     tx_driver
-                        .write_all_async(&hello_audio)
+                        .write_all_async(&with_volume(&hello_audio))
                         .await
                         .map_err(|e| anyhow::anyhow!("Error play set hello: {:?}", e))?;
                 }
@@ -312,7 +350,7 @@ async fn i2s_player_(
                     log::info!("Received audio chunk");
                     if speaking {
                         tx_driver
-                            .write_all_async(&data)
+                            .write_all_async(&with_volume(&data))
                             .await
                             .map_err(|e| anyhow::anyhow!("Error play audio data: {:?}", e))?;
                     }
@@ -340,10 +378,11 @@ pub async fn i2s_task(
     ws: AnyIOPin,
     (tx, rx): (MicTx, PlayerRx),
     afe_handle: Arc<AFE>, // 添加AFE实例参数
+    hello_audio: Vec<u8>,
 ) {
     let afe_handle_ = afe_handle.clone();
     let afe_r = std::thread::spawn(|| afe_worker(afe_handle_, tx));
-    let r = i2s_player(i2s, bclk, din, dout, ws, afe_handle, rx).await;
+    let r = i2s_player(i2s, bclk, din, dout, ws, afe_handle, rx, hello_audio).await;
     if let Err(e) = r {
         log::error!("Error: {}", e);
     } else {
@@ -365,6 +404,7 @@ async fn i2s_player(
     ws: AnyIOPin,
     afe_handle: Arc<AFE>,
     mut rx: PlayerRx,
+    mut hello_audio: Vec<u8>,
 ) -> anyhow::Result<()> {
     log::info!("PORT_TICK_PERIOD_MS = {}", PORT_TICK_PERIOD_MS);
     let i2s_config = config::StdConfig::new(
@@ -386,9 +426,7 @@ async fn i2s_player(
     let mut buf = [0u8; 2 * 160];
     let mut speaking = false;
 
-    let mut hello_audio = WAKE_WAV.to_vec();
-
-    driver.write_all(&hello_audio, 100 / PORT_TICK_PERIOD_MS)?;
+    driver.write_all(&with_volume(&hello_audio), 100 / PORT_TICK_PERIOD_MS)?;
     log::info!("Playing hello audio, waiting for response...");
 
     loop {
@@ -411,7 +449,7 @@ async fn i2s_player(
                 AudioData::Hello(tx) => {
                     log::info!("Received hello");
                     driver
-                        .write_all_async(&hello_audio)
+                        .write_all_async(&with_volume(&hello_audio))
                         .await
                         .map_err(|e| anyhow::anyhow!("Error play hello: {:?}", e))?;
                     log::info!("Hello audio sent, notifying");
@@ -419,18 +457,11 @@ async fn i2s_player(
                     log::info!("Hello audio sent, notifying done");
                     speaking = false;
                 }
-                AudioData::SetHelloStart => {
-                    log::info!("Received set hello start");
-                    hello_audio.clear();
-                }
-                AudioData::SetHelloChunk(data) => {
-                    log::info!("Received set hello chunk");
-                    hello_audio.extend(data);
-                }
-                AudioData::SetHelloEnd => {
-                    log::info!("Received set hello end");
+                AudioData::SetHello(data) => {
+                    log::info!("Received set hello");
+                    hello_audio = data;
                     driver
-                        .write_all_async(&hello_audio)
+                        .write_all_async(&with_volume(&hello_audio))
                         .await
                         .map_err(|e| anyhow::anyhow!("Error play set hello: {:?}", e))?;
                 }
@@ -442,7 +473,7 @@ async fn i2s_player(
                     log::info!("Received audio chunk");
                     if speaking {
                         driver
-                            .write_all_async(&data)
+                            .write_all_async(&with_volume(&data))
                             .await
                             .map_err(|e| anyhow::anyhow!("Error play audio data: {:?}", e))?;
                     }
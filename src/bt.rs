@@ -1,17 +1,366 @@
 use std::sync::{Arc, Mutex};
 
-use esp32_nimble::{utilities::BleUuid, uuid128, BLEAdvertisementData, NimbleProperties};
+use esp32_nimble::{
+    enums::{AuthReq, SecurityIOCap},
+    utilities::BleUuid,
+    uuid128, BLEAdvertisementData, NimbleProperties,
+};
+
+use crate::protocol::{AssetKind, Checksum};
+use crate::transfer::{Transfer, Transfers, UploadCommand, UploadStatus};
 
 const SERVICE_ID: BleUuid = uuid128!("623fa3e2-631b-4f8f-a6e7-a7b09c03e7e0");
 const SSID_ID: BleUuid = uuid128!("1fda4d6e-2f14-42b0-96fa-453bed238375");
 const PASS_ID: BleUuid = uuid128!("a987ab18-a940-421a-a1d7-b94ee22bccbe");
 const SERVER_URL_ID: BleUuid = uuid128!("cef520a9-bcb5-4fc6-87f7-82804eee2b20");
 const BACKGROUND_GIF_ID: BleUuid = uuid128!("d1f3b2c4-5e6f-4a7b-8c9d-0e1f2a3b4c5d");
+const NETWORKS_ID: BleUuid = uuid128!("5b8e6c2a-7f3d-4e91-a2c4-3d6f8b1e9a70");
+const IP_CONFIG_ID: BleUuid = uuid128!("0c7d4a1e-93b2-4f6a-8e5d-2b9c71f04a36");
+const WIFI_SCAN_ID: BleUuid = uuid128!("e4a1c9b3-2d57-4f08-b6e3-91c8a5d2f714");
+const TEST_CONNECTION_ID: BleUuid = uuid128!("9f2b7d41-6c8e-4a35-b1d9-5e0a3c7f8b62");
+const SETTINGS_ID: BleUuid = uuid128!("3a6f1d92-8b4e-4c07-9e25-c1b8d7a0f463");
+const STATUS_ID: BleUuid = uuid128!("6e0b3f58-d2a9-4c71-8f14-a5c29e7d3b08");
+const FACTORY_RESET_ID: BleUuid = uuid128!("b7c25e09-4a1d-4f3e-9c68-2e5d0f8a6b14");
+const BUNDLE_ID: BleUuid = uuid128!("4d9a2c71-0e6b-4f58-a3d2-7c1e5b8f0a93");
 
-pub fn bt(
-    setting: Arc<Mutex<(super::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+const GIT_HASH: &str = env!("ECHOKIT_GIT_HASH");
+
+/// How often upload progress is notified, in bytes.
+const UPLOAD_PROGRESS_STEP: u32 = 16 * 1024;
+
+/// Settings can only be written over an encrypted, authenticated (passkey)
+/// link.
+const CONFIG_WRITE: NimbleProperties = NimbleProperties::WRITE
+    .union(NimbleProperties::WRITE_ENC)
+    .union(NimbleProperties::WRITE_AUTHEN);
+
+pub type SharedSetting = Arc<Mutex<(super::Setting, crate::secrets::SealedNvs)>>;
+pub type SharedWifi = Arc<Mutex<Box<esp_idf_svc::wifi::EspWifi<'static>>>>;
+
+/// Saves the SSID/PASS pair written over BLE (or the setup portal) as one of
+/// the known networks.
+pub fn save_network(
+    setting: &mut (super::Setting, crate::secrets::SealedNvs),
+) -> anyhow::Result<()> {
+    let (setting, nvs) = setting;
+    if setting.ssid.is_empty() {
+        return Ok(());
+    }
+    // keep the settings of a network that is already saved
+    let mut network = setting
+        .networks
+        .list()
+        .iter()
+        .find(|n| n.ssid == setting.ssid)
+        .cloned()
+        .unwrap_or_default();
+    network.ssid = setting.ssid.clone();
+    network.pass = setting.pass.clone();
+    let r = setting
+        .networks
+        .add(network)
+        .and_then(|_| crate::networks::save(nvs, &setting.networks));
+    if let Err(e) = &r {
+        log::error!("Failed to save network {}: {:?}", setting.ssid, e);
+    }
+    r
+}
+
+/// Saves a validated setting and mirrors it in `Setting`.
+pub fn save_setting(
+    setting: &mut (super::Setting, crate::secrets::SealedNvs),
+    def: &crate::settings::Def,
+    value: &crate::settings::Value,
+) -> anyhow::Result<()> {
+    crate::settings::save(&mut setting.1, def, value)?;
+    if def.secret {
+        log::info!("New {} saved", def.key);
+    } else {
+        log::info!("New {}: {:?}", def.key, value);
+    }
+    setting.0.apply(def.key, value);
+    Ok(())
+}
+
+/// Progress of a connection test, notified as JSON, e.g.
+/// `{"step":"got_ip","detail":"192.168.1.20"}`.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "step", content = "detail", rename_all = "snake_case")]
+enum TestStep {
+    Started,
+    Associated,
+    GotIp(String),
+    ServerReachable,
+    HandshakeOk,
+    Error(String),
+}
+
+/// Tries the network for the SSID set over BLE and the server URL, the way
+/// the device will use them after the restart.
+fn test_connection(
+    setting: &SharedSetting,
+    wifi: &SharedWifi,
+    report: &mut dyn FnMut(TestStep),
 ) -> anyhow::Result<()> {
+    let (network, ip, settings) = {
+        let setting = setting.lock().unwrap();
+        let network = setting
+            .0
+            .networks
+            .list()
+            .iter()
+            .find(|n| n.ssid == setting.0.ssid)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Set the WiFi SSID and password first"))?;
+        (network, setting.0.ip.clone(), setting.0.settings.clone())
+    };
+    let server_url = &settings.server_url;
+    if server_url.is_empty() {
+        anyhow::bail!("Set the server URL first");
+    }
+
+    let r = (|| -> anyhow::Result<()> {
+        let ip_info =
+            crate::network::try_join(wifi, &network, &ip, || report(TestStep::Associated))?;
+        report(TestStep::GotIp(ip_info.ip.to_string()));
+
+        let uri: http::Uri = server_url.parse()?;
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow::anyhow!("No host in {}", server_url))?;
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("wss") {
+                443
+            } else {
+                80
+            });
+        let addr = std::net::ToSocketAddrs::to_socket_addrs(&(host, port))
+            .map_err(|e| anyhow::anyhow!("Failed to resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Failed to resolve {}", host))?;
+        std::net::TcpStream::connect_timeout(&addr, std::time::Duration::from_secs(5))
+            .map_err(|e| anyhow::anyhow!("Server {}:{} is unreachable: {}", host, port, e))?;
+        report(TestStep::ServerReachable);
+
+        let url = format!("{}{}", server_url, crate::network::device_id());
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(async {
+                tokio::time::timeout(
+                    std::time::Duration::from_secs(10),
+                    crate::ws::Server::new(url, &settings),
+                )
+                .await
+                .map_err(|_| anyhow::anyhow!("Timeout connecting to the server"))?
+            })
+            .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {}", e))?;
+        report(TestStep::HandshakeOk);
+        Ok(())
+    })();
+
+    // stay off the network until the restart, scans need an idle station
+    let _ = wifi.lock().unwrap().disconnect();
+    r
+}
+
+fn network_scan(wifi: &SharedWifi) -> anyhow::Result<Vec<u8>> {
+    let entries = crate::network::scan(&mut wifi.lock().unwrap())?;
+    log::info!("Wifi scan found {} APs", entries.len());
+    Ok(crate::networks::encode_scan(entries))
+}
+
+const BACKGROUND_UPLOAD: u32 = 0;
+
+/// Steps a background upload, the GIF is kept in the setting once it has been
+/// verified and is saved when K0 is pressed.
+fn background_upload(
+    upload: &mut Transfers,
+    setting: &SharedSetting,
+    cmd: UploadCommand,
+) -> anyhow::Result<Option<UploadStatus>> {
+    match cmd {
+        UploadCommand::Begin { size, crc32 } => {
+            let transfer = Transfer::new(
+                BACKGROUND_UPLOAD,
+                AssetKind::Background,
+                None,
+                size,
+                Checksum::Crc32(crc32),
+            )?;
+            let received = upload.start(transfer);
+            log::info!("Background upload of {} bytes from {}", size, received);
+            setting.lock().unwrap().0.background_gif = (Vec::new(), false);
+            Ok(Some(UploadStatus::Progress { received }))
+        }
+        UploadCommand::Chunk { offset, data } => {
+            upload_chunk(upload, BACKGROUND_UPLOAD, offset, data)
+        }
+        UploadCommand::Commit => {
+            let (_, _, gif) = upload.commit(BACKGROUND_UPLOAD)?;
+            crate::ui::check_gif(&gif)?;
+            log::info!("New background GIF received, size: {}", gif.len());
+            setting.lock().unwrap().0.background_gif = (gif, true);
+            Ok(Some(UploadStatus::Done))
+        }
+        UploadCommand::Abort => {
+            upload.abort(BACKGROUND_UPLOAD);
+            log::info!("Background upload aborted");
+            Ok(Some(UploadStatus::Aborted))
+        }
+    }
+}
+
+/// Progress is only notified every `UPLOAD_PROGRESS_STEP` bytes, and when the
+/// sender has to rewind.
+fn upload_chunk<K: Copy + PartialEq>(
+    upload: &mut Transfers<K>,
+    id: u32,
+    offset: u32,
+    data: &[u8],
+) -> anyhow::Result<Option<UploadStatus>> {
+    if let Some(received) = upload.chunk(id, offset, data)? {
+        log::warn!("Upload {} chunk at {}, expected {}", id, offset, received);
+        return Ok(Some(UploadStatus::Progress { received }));
+    }
+    let received = offset + data.len() as u32;
+    if received / UPLOAD_PROGRESS_STEP != offset / UPLOAD_PROGRESS_STEP {
+        Ok(Some(UploadStatus::Progress { received }))
+    } else {
+        Ok(None)
+    }
+}
+
+const BUNDLE_UPLOAD: u32 = 1;
+
+/// Work for the bundle thread. Imports write to flash, which takes too long
+/// for the write callback.
+enum BundleJob {
+    Notify(UploadStatus),
+    Import(Vec<u8>),
+}
+
+/// Steps a bundle upload, like `background_upload` but the bundle isn't an
+/// asset and is imported as soon as it is complete.
+fn bundle_upload(
+    upload: &mut Transfers<()>,
+    cmd: UploadCommand,
+) -> anyhow::Result<Option<BundleJob>> {
+    match cmd {
+        UploadCommand::Begin { size, crc32 } => {
+            let transfer = Transfer::new(BUNDLE_UPLOAD, (), None, size, Checksum::Crc32(crc32))?;
+            let received = upload.start(transfer);
+            log::info!("Bundle upload of {} bytes from {}", size, received);
+            Ok(Some(BundleJob::Notify(UploadStatus::Progress { received })))
+        }
+        UploadCommand::Chunk { offset, data } => {
+            Ok(upload_chunk(upload, BUNDLE_UPLOAD, offset, data)?.map(BundleJob::Notify))
+        }
+        UploadCommand::Commit => {
+            let (_, _, data) = upload.commit(BUNDLE_UPLOAD)?;
+            Ok(Some(BundleJob::Import(data)))
+        }
+        UploadCommand::Abort => {
+            upload.abort(BUNDLE_UPLOAD);
+            log::info!("Bundle upload aborted");
+            Ok(Some(BundleJob::Notify(UploadStatus::Aborted)))
+        }
+    }
+}
+
+/// Returned as JSON by the status characteristic, for support requests.
+#[derive(serde::Serialize)]
+struct DeviceStatus<'a> {
+    firmware: &'static str,
+    git_hash: &'static str,
+    board: &'static str,
+    features: Vec<&'static str>,
+    device_id: &'a str,
+    free_heap_internal: usize,
+    free_heap_spiram: usize,
+    reset_reason: &'static str,
+    last_error: Option<&'a str>,
+}
+
+#[allow(non_upper_case_globals)]
+fn reset_reason() -> &'static str {
+    use esp_idf_svc::sys::*;
+
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+/// The device status as JSON, also printed by the serial console.
+pub fn device_status(setting: &super::Setting) -> Vec<u8> {
+    let device_id = crate::network::device_id();
+    let (free_heap_internal, free_heap_spiram) = crate::free_heap();
+    let features = [
+        ("boards", cfg!(feature = "boards")),
+        ("box", cfg!(feature = "box")),
+        ("experimental", cfg!(feature = "experimental")),
+    ];
+    let status = DeviceStatus {
+        firmware: env!("CARGO_PKG_VERSION"),
+        git_hash: GIT_HASH,
+        board: crate::hal::BOARD,
+        features: features.iter().filter(|f| f.1).map(|f| f.0).collect(),
+        device_id: &device_id,
+        free_heap_internal,
+        free_heap_spiram,
+        reset_reason: reset_reason(),
+        last_error: setting.last_error.as_deref(),
+    };
+    serde_json::to_vec(&status).unwrap_or_default()
+}
+
+/// Adds the standard Device Information Service (0x180A).
+fn device_information(server: &mut esp32_nimble::BLEServer, device_id: &str) {
+    let service = server.create_service(BleUuid::from_uuid16(0x180A));
+    let fields = [
+        (0x2A29, "EchoKit"),                 // manufacturer name
+        (0x2A24, crate::hal::BOARD),         // model number
+        (0x2A25, device_id),                 // serial number
+        (0x2A26, env!("CARGO_PKG_VERSION")), // firmware revision
+        (0x2A28, GIT_HASH),                  // software revision
+    ];
+    for (uuid, value) in fields {
+        service
+            .lock()
+            .create_characteristic(BleUuid::from_uuid16(uuid), NimbleProperties::READ)
+            .lock()
+            .set_value(value.as_bytes());
+    }
+}
+
+/// Starts the setup service, and Improv next to it. Returns the pairing
+/// passkey, which has to be shown to the user.
+pub fn bt(
+    setting: SharedSetting,
+    wifi: SharedWifi,
+    assets: crate::assets::SharedAssets,
+    improv: Arc<crate::improv::Improv>,
+) -> anyhow::Result<u32> {
     let ble_device = esp32_nimble::BLEDevice::take();
+    // LE Secure Connections with MITM protection, the passkey is new on every boot
+    let passkey = unsafe { esp_idf_svc::sys::esp_random() } % 1_000_000;
+    ble_device
+        .security()
+        .set_auth(AuthReq::all())
+        .set_passkey(passkey)
+        .set_io_cap(SecurityIOCap::DisplayOnly)
+        .resolve_rpa();
     let ble_addr = ble_device.get_addr()?.to_string();
     let ble_advertising = ble_device.get_advertising();
 
@@ -33,14 +382,101 @@ pub fn bt(
         log::info!("Client disconnected ({:?})", reason);
     });
 
+    device_information(server, &crate::network::device_id());
+    crate::improv::ble_service(server, ble_advertising, improv)?;
+
     let service = server.create_service(SERVICE_ID);
 
+    let setting_status = setting.clone();
+    let status_characteristic = service
+        .lock()
+        .create_characteristic(STATUS_ID, NimbleProperties::READ);
+    status_characteristic.lock().on_read(move |c, _| {
+        log::info!("Read from status characteristic");
+        let setting = setting_status.lock().unwrap();
+        c.set_value(&device_status(&setting.0));
+    });
+
+    // Chunked bundle upload, framed like the background upload. The import
+    // report can be read back once `done` is notified.
+    let bundle_characteristic = service.lock().create_characteristic(
+        BUNDLE_ID,
+        NimbleProperties::READ | CONFIG_WRITE | NimbleProperties::NOTIFY,
+    );
+    let (bundle_tx, bundle_rx) = std::sync::mpsc::channel::<BundleJob>();
+    let characteristic = bundle_characteristic.clone();
+    let setting_bundle = setting.clone();
+    let assets_bundle = assets.clone();
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            for job in bundle_rx {
+                let (status, report) = match job {
+                    BundleJob::Notify(status) => (status, None),
+                    BundleJob::Import(data) => {
+                        match crate::bundle::import(&setting_bundle, &assets_bundle, &data) {
+                            Ok(report) => (UploadStatus::Done, serde_json::to_vec(&report).ok()),
+                            Err(e) => {
+                                log::error!("Bundle import failed: {:?}", e);
+                                let message = e.to_string();
+                                (UploadStatus::Error { message }, None)
+                            }
+                        }
+                    }
+                };
+                let data = serde_json::to_vec(&status).unwrap_or_default();
+                let mut characteristic = characteristic.lock();
+                characteristic.set_value(&data).notify();
+                if let Some(report) = report {
+                    characteristic.set_value(&report);
+                }
+            }
+        })?;
+    let mut bundle_transfers = Transfers::default();
+    bundle_characteristic.lock().on_write(move |args| {
+        let r = UploadCommand::parse(args.recv_data(), crate::transfer::MAX_ASSET_SIZE)
+            .and_then(|cmd| bundle_upload(&mut bundle_transfers, cmd));
+        let job = match r {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("Bundle upload failed: {:?}", e);
+                args.reject();
+                BundleJob::Notify(UploadStatus::Error {
+                    message: e.to_string(),
+                })
+            }
+        };
+        let _ = bundle_tx.send(job);
+    });
+
+    // `reset::CONFIRM` wipes the device, see `reset::factory_reset`
+    let factory_reset_characteristic = service
+        .lock()
+        .create_characteristic(FACTORY_RESET_ID, CONFIG_WRITE);
+    factory_reset_characteristic.lock().on_write(move |args| {
+        if args.recv_data() != crate::reset::CONFIRM {
+            log::warn!("Factory reset not confirmed: {:?}", args.recv_data());
+            args.reject();
+            return;
+        }
+        let assets = assets.clone();
+        let r = std::thread::Builder::new()
+            .stack_size(8 * 1024)
+            .spawn(move || crate::reset::factory_reset(&assets));
+        if let Err(e) = r {
+            log::error!("Failed to start factory reset: {:?}", e);
+            args.reject();
+        }
+    });
+    let setting_test = setting.clone();
+
     let setting1 = setting.clone();
     let setting2 = setting.clone();
 
     let ssid_characteristic = service
         .lock()
-        .create_characteristic(SSID_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+        .create_characteristic(SSID_ID, NimbleProperties::READ | CONFIG_WRITE);
     ssid_characteristic
         .lock()
         .on_read(move |c, _| {
@@ -57,44 +493,141 @@ pub fn bt(
             if let Ok(new_ssid) = String::from_utf8(args.recv_data().to_vec()) {
                 log::info!("New SSID: {}", new_ssid);
                 let mut setting = setting2.lock().unwrap();
-                if let Err(e) = setting.1.set_str("ssid", &new_ssid) {
-                    log::error!("Failed to save SSID to NVS: {:?}", e);
-                } else {
-                    setting.0.ssid = new_ssid;
-                }
+                // keep the password of a network that is already saved, a new
+                // one is saved once its password is written
+                setting.0.pass = setting
+                    .0
+                    .networks
+                    .list()
+                    .iter()
+                    .find(|n| n.ssid == new_ssid)
+                    .map(|n| n.pass.clone())
+                    .unwrap_or_default();
+                setting.0.ssid = new_ssid;
             } else {
                 log::error!("Failed to parse new SSID from bytes.");
             }
         });
 
+    // write-only, the password never leaves the device. Writing it saves the
+    // network for the SSID written before, an empty one for open networks.
+    let setting2 = setting.clone();
+    let pass_characteristic = service.lock().create_characteristic(PASS_ID, CONFIG_WRITE);
+    pass_characteristic.lock().on_write(move |args| {
+        log::info!(
+            "Wrote to pass characteristic ({} bytes)",
+            args.recv_data().len()
+        );
+        if let Ok(new_pass) = String::from_utf8(args.recv_data().to_vec()) {
+            let mut setting = setting2.lock().unwrap();
+            setting.0.pass = new_pass;
+            let _ = save_network(&mut setting);
+        } else {
+            log::error!("Failed to parse new pass from bytes.");
+        }
+    });
+
     let setting1 = setting.clone();
     let setting2 = setting.clone();
-    let pass_characteristic = service
+    let networks_characteristic = service
         .lock()
-        .create_characteristic(PASS_ID, NimbleProperties::READ | NimbleProperties::WRITE);
-    pass_characteristic
+        .create_characteristic(NETWORKS_ID, NimbleProperties::READ | CONFIG_WRITE);
+    networks_characteristic
         .lock()
         .on_read(move |c, _| {
-            log::info!("Read from pass characteristic");
+            log::info!("Read from networks characteristic");
             let setting = setting1.lock().unwrap();
-            c.set_value(setting.0.pass.as_bytes());
+            c.set_value(setting.0.networks.summary_json().as_bytes());
         })
         .on_write(move |args| {
-            log::info!(
-                "Wrote to pass characteristic: {:?} -> {:?}",
-                args.current_data(),
-                args.recv_data()
-            );
-            if let Ok(new_pass) = String::from_utf8(args.recv_data().to_vec()) {
-                log::info!("New pass: {}", new_pass);
-                let mut setting = setting2.lock().unwrap();
-                if let Err(e) = setting.1.set_str("pass", &new_pass) {
-                    log::error!("Failed to save pass to NVS: {:?}", e);
-                } else {
-                    setting.0.pass = new_pass;
+            let cmd = match serde_json::from_slice(args.recv_data()) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    // the error can quote the input, which may hold a password
+                    log::error!("Invalid networks command at column {}", e.column());
+                    args.reject();
+                    return;
+                }
+            };
+            let mut setting = setting2.lock().unwrap();
+            let (setting, nvs) = &mut *setting;
+            let r = setting
+                .networks
+                .apply(cmd)
+                .and_then(|_| crate::networks::save(nvs, &setting.networks));
+            match r {
+                Ok(()) => log::info!("Saved networks: {}", setting.networks.summary_json()),
+                Err(e) => {
+                    log::error!("Failed to update networks: {:?}", e);
+                    args.reject();
+                }
+            }
+        });
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let ip_config_characteristic = service
+        .lock()
+        .create_characteristic(IP_CONFIG_ID, NimbleProperties::READ | CONFIG_WRITE);
+    ip_config_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from IP config characteristic");
+            let setting = setting1.lock().unwrap();
+            c.set_value(&serde_json::to_vec(&setting.0.ip).unwrap_or_default());
+        })
+        .on_write(move |args| {
+            let ip: crate::networks::IpSettings = match serde_json::from_slice(args.recv_data()) {
+                Ok(ip) => ip,
+                Err(e) => {
+                    log::error!("Invalid IP config: {:?}", e);
+                    args.reject();
+                    return;
+                }
+            };
+            let mut setting = setting2.lock().unwrap();
+            match crate::networks::save_ip(&mut setting.1, &ip) {
+                Ok(()) => {
+                    log::info!("New IP config: {:?}", ip);
+                    setting.0.ip = ip;
+                }
+                Err(e) => {
+                    log::error!("Failed to save IP config: {:?}", e);
+                    args.reject();
                 }
-            } else {
-                log::error!("Failed to parse new pass from bytes.");
+            }
+        });
+
+    // Any setting in `settings::SCHEMA` is written here as `{key, value}`,
+    // reading returns the schema with the current values.
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let settings_characteristic = service
+        .lock()
+        .create_characteristic(SETTINGS_ID, NimbleProperties::READ | CONFIG_WRITE);
+    settings_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from settings characteristic");
+            let setting = setting1.lock().unwrap();
+            c.set_value(&crate::settings::schema_json(|def| {
+                crate::settings::load(&setting.1, def)
+            }));
+        })
+        .on_write(move |args| {
+            let r = crate::settings::Write::parse(args.recv_data()).and_then(|w| w.validate());
+            let (def, value) = match r {
+                Ok(r) => r,
+                Err(e) => {
+                    log::error!("Invalid setting: {:?}", e);
+                    args.reject();
+                    return;
+                }
+            };
+            let mut setting = setting2.lock().unwrap();
+            if let Err(e) = save_setting(&mut setting, def, &value) {
+                log::error!("Failed to save {}: {:?}", def.key, e);
+                args.reject();
             }
         });
 
@@ -102,16 +635,15 @@ pub fn bt(
     let setting_ = setting.clone();
     let setting_gif = setting.clone();
 
-    let server_url_characteristic = service.lock().create_characteristic(
-        SERVER_URL_ID,
-        NimbleProperties::READ | NimbleProperties::WRITE,
-    );
+    let server_url_characteristic = service
+        .lock()
+        .create_characteristic(SERVER_URL_ID, NimbleProperties::READ | CONFIG_WRITE);
     server_url_characteristic
         .lock()
         .on_read(move |c, _| {
             log::info!("Read from server URL characteristic");
             let setting = setting.lock().unwrap();
-            c.set_value(setting.0.server_url.as_bytes());
+            c.set_value(setting.0.settings.server_url.as_bytes());
         })
         .on_write(move |args| {
             log::info!(
@@ -119,37 +651,122 @@ pub fn bt(
                 args.current_data(),
                 args.recv_data()
             );
-            if let Ok(mut new_server_url) = String::from_utf8(args.recv_data().to_vec()) {
-                log::info!("New server URL: {}", new_server_url);
-                if !new_server_url.ends_with("/") {
-                    new_server_url.push('/');
-                }
-                let mut setting = setting_.lock().unwrap();
-                if let Err(e) = setting.1.set_str("server_url", &new_server_url) {
-                    log::error!("Failed to save server URL to NVS: {:?}", e);
-                } else {
-                    setting.0.server_url = new_server_url;
-                }
-            } else {
-                log::error!("Failed to parse new server URL from bytes.");
+            let r = String::from_utf8(args.recv_data().to_vec())
+                .map_err(anyhow::Error::from)
+                .and_then(|url| {
+                    let def = crate::settings::def("server_url")?;
+                    let url = def.validate(crate::settings::Value::Text(url))?;
+                    save_setting(&mut setting_.lock().unwrap(), def, &url)
+                });
+            if let Err(e) = r {
+                log::error!("Failed to save server URL: {:?}", e);
+                args.reject();
             }
         });
 
+    // Chunked background upload, see `transfer::UploadCommand`. Replies are
+    // notified from their own thread, not from inside the write callback.
     let background_gif_characteristic = service
         .lock()
-        .create_characteristic(BACKGROUND_GIF_ID, NimbleProperties::WRITE);
+        .create_characteristic(BACKGROUND_GIF_ID, CONFIG_WRITE | NimbleProperties::NOTIFY);
+    let (status_tx, status_rx) = std::sync::mpsc::channel::<UploadStatus>();
+    let gif_characteristic = background_gif_characteristic.clone();
+    std::thread::Builder::new()
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            for status in status_rx {
+                let data = serde_json::to_vec(&status).unwrap_or_default();
+                gif_characteristic.lock().set_value(&data).notify();
+            }
+        })?;
+    let mut upload = Transfers::default();
     background_gif_characteristic.lock().on_write(move |args| {
-        let gif_chunk = args.recv_data();
-
-        if gif_chunk.len() <= 1024 * 1024 && gif_chunk.len() > 0 {
-            log::info!("New background GIF received, size: {}", gif_chunk.len());
-            let mut setting = setting_gif.lock().unwrap();
-            setting.0.background_gif.0.extend_from_slice(gif_chunk);
-            if gif_chunk.len() < 512 {
-                setting.0.background_gif.1 = true; // Mark as valid
+        let r = UploadCommand::parse(args.recv_data(), crate::transfer::MAX_GIF_SIZE)
+            .and_then(|cmd| background_upload(&mut upload, &setting_gif, cmd));
+        let status = match r {
+            Ok(Some(status)) => status,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("Background upload failed: {:?}", e);
+                args.reject();
+                UploadStatus::Error {
+                    message: e.to_string(),
+                }
             }
-        } else {
-            log::error!("Failed to parse new background GIF from bytes.");
+        };
+        let _ = status_tx.send(status);
+    });
+
+    // Writing anything over a paired link, like the settings, starts a scan.
+    // The result is notified when it is done and can be read back afterwards
+    // (see `networks::encode_scan`).
+    let wifi_scan_characteristic = service.lock().create_characteristic(
+        WIFI_SCAN_ID,
+        NimbleProperties::READ | CONFIG_WRITE | NimbleProperties::NOTIFY,
+    );
+    let scan_characteristic = wifi_scan_characteristic.clone();
+    let wifi_test = wifi.clone();
+    let scanning = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    wifi_scan_characteristic.lock().on_write(move |_| {
+        if scanning.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            log::info!("Wifi scan already running");
+            return;
+        }
+        let wifi = wifi.clone();
+        let characteristic = scan_characteristic.clone();
+        let scanning = scanning.clone();
+        let r = std::thread::Builder::new()
+            .stack_size(8 * 1024)
+            .spawn(move || {
+                let r = network_scan(&wifi);
+                scanning.store(false, std::sync::atomic::Ordering::SeqCst);
+                match r {
+                    Ok(data) => {
+                        characteristic.lock().set_value(&data).notify();
+                    }
+                    Err(e) => log::error!("Wifi scan failed: {:?}", e),
+                }
+            });
+        if let Err(e) = r {
+            log::error!("Failed to start wifi scan: {:?}", e);
+            scanning.store(false, std::sync::atomic::Ordering::SeqCst);
+        }
+    });
+
+    // Writing anything over a paired link runs a connection test, each step
+    // is notified as a `TestStep` and the last one can be read back.
+    let test_connection_characteristic = service.lock().create_characteristic(
+        TEST_CONNECTION_ID,
+        NimbleProperties::READ | CONFIG_WRITE | NimbleProperties::NOTIFY,
+    );
+    let test_characteristic = test_connection_characteristic.clone();
+    let testing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    test_connection_characteristic.lock().on_write(move |_| {
+        if testing.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            log::info!("Connection test already running");
+            return;
+        }
+        let setting = setting_test.clone();
+        let wifi = wifi_test.clone();
+        let characteristic = test_characteristic.clone();
+        let testing = testing.clone();
+        let r = std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || {
+                let mut report = |step: TestStep| {
+                    log::info!("Connection test: {:?}", step);
+                    let data = serde_json::to_vec(&step).unwrap_or_default();
+                    characteristic.lock().set_value(&data).notify();
+                };
+                report(TestStep::Started);
+                if let Err(e) = test_connection(&setting, &wifi, &mut report) {
+                    report(TestStep::Error(e.to_string()));
+                }
+                testing.store(false, std::sync::atomic::Ordering::SeqCst);
+            });
+        if let Err(e) = r {
+            log::error!("Failed to start connection test: {:?}", e);
+            testing.store(false, std::sync::atomic::Ordering::SeqCst);
         }
     });
 
@@ -159,5 +776,5 @@ pub fn bt(
             .add_service_uuid(SERVICE_ID),
     )?;
     ble_advertising.lock().start()?;
-    Ok(())
+    Ok(passkey)
 }
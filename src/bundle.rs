@@ -0,0 +1,79 @@
+pub use echokit_core::bundle::*;
+
+use crate::settings;
+
+/// Build with `ECHOKIT_BUNDLE_PUBKEY=<hex ed25519 public key>` to only accept
+/// bundles signed with the matching private key.
+const BUNDLE_PUBKEY: Option<&str> = option_env!("ECHOKIT_BUNDLE_PUBKEY");
+
+/// Imports a bundle from BLE, the console or the setup portal. Settings take
+/// effect right away, networks and assets with the next restart.
+///
+/// Assets go last: they are the writes that can run out of space, and the
+/// error then says the rest was imported.
+pub fn import(
+    setting: &crate::bt::SharedSetting,
+    assets: &crate::assets::SharedAssets,
+    data: &[u8],
+) -> anyhow::Result<Report> {
+    let config = Bundle::parse(data)?.open(BUNDLE_PUBKEY)?;
+    let plan = config.plan(&setting.lock().unwrap().0.networks)?;
+    // backgrounds are decoded at boot, like a BLE upload they have to be a
+    // GIF that decodes
+    for asset in &plan.assets {
+        if asset.kind == crate::protocol::AssetKind::Background {
+            crate::ui::check_gif(&asset.data)
+                .map_err(|e| anyhow::anyhow!("Asset {:?}: {}", asset.name, e))?;
+        }
+    }
+    let mut report = Report {
+        skipped: plan.skipped,
+        ..Default::default()
+    };
+
+    let mut setting = setting.lock().unwrap();
+    if let Some(networks) = plan.networks {
+        crate::networks::save(&mut setting.1, &networks)?;
+        let top = networks.list().first().cloned().unwrap_or_default();
+        report.networks = networks.list().len();
+        setting.0.ssid = top.ssid;
+        setting.0.pass = top.pass;
+        setting.0.networks = networks;
+    }
+    for (def, value) in plan.settings {
+        crate::bt::save_setting(&mut setting, def, &value)?;
+        report.settings.push(def.key);
+    }
+    drop(setting);
+
+    let mut assets = assets.lock().unwrap();
+    for asset in plan.assets {
+        let stored = assets
+            .put(&asset.name, asset.kind, &asset.data)
+            .and_then(|_| {
+                if asset.active {
+                    assets.set_active(asset.kind, Some(&asset.name))
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(e) = stored {
+            anyhow::bail!(
+                "Asset {} not stored, the networks, settings and assets {:?} were imported: {}",
+                asset.name,
+                report.assets,
+                e
+            );
+        }
+        report.assets.push(asset.name);
+    }
+    log::info!("Bundle imported: {:?}", report);
+    Ok(report)
+}
+
+/// This device's networks and settings as bundle text, see `Config::export`.
+pub fn export(setting: &crate::bt::SharedSetting) -> anyhow::Result<String> {
+    let setting = setting.lock().unwrap();
+    let config = Config::export(&setting.0.networks, |def| settings::load(&setting.1, def));
+    Bundle::new(&config)?.to_text()
+}
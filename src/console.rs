@@ -0,0 +1,159 @@
+pub use echokit_core::console::*;
+
+/// What the console works on, shared with the rest of the firmware.
+pub struct Console {
+    pub setting: crate::bt::SharedSetting,
+    pub assets: crate::assets::SharedAssets,
+    pub player: crate::audio::PlayerTx,
+    /// Improv packets share the serial input with the commands.
+    pub improv: std::sync::Arc<crate::improv::Improv>,
+}
+
+/// Runs the console on stdin/stdout, i.e. the UART or USB-JTAG the log goes
+/// to, from a background thread.
+pub fn start(console: Console) -> anyhow::Result<()> {
+    std::thread::Builder::new()
+        .name("console".to_string())
+        .stack_size(8 * 1024)
+        .spawn(move || run(console))?;
+    Ok(())
+}
+
+fn run(console: Console) {
+    use std::io::{Read, Write};
+
+    let mut stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    let mut lines = LineBuffer::default();
+    let mut improv = crate::improv::SerialDecoder::default();
+    let mut buf = [0u8; 64];
+    loop {
+        // stdin doesn't block without a UART driver, poll it
+        let n = match stdin.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+            Err(e) => {
+                log::error!("Console read failed: {:?}", e);
+                0
+            }
+        };
+        if n == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            continue;
+        }
+
+        for &byte in &buf[..n] {
+            let text = match improv.push(byte) {
+                None => continue,
+                Some(crate::improv::Input::Text(text)) => text,
+                Some(input) => {
+                    if let Err(e) = console.improv.serial(input, &mut stdout) {
+                        log::error!("Failed to answer Improv: {:?}", e);
+                    }
+                    continue;
+                }
+            };
+            for byte in text {
+                // echo, the monitor doesn't
+                let _ = match byte {
+                    b'\r' | b'\n' => stdout.write_all(b"\r\n"),
+                    0x08 | 0x7f => stdout.write_all(b"\x08 \x08"),
+                    _ => stdout.write_all(&[byte]),
+                };
+                let Some(line) = lines.push(byte) else {
+                    continue;
+                };
+                match Command::parse(&line) {
+                    Ok(Some(cmd)) => match execute(&console, cmd) {
+                        Ok(output) => println!("{}", output.trim_end()),
+                        Err(e) => println!("Error: {}", e),
+                    },
+                    Ok(None) => {}
+                    Err(e) => println!("Error: {}", e),
+                }
+                print!("> ");
+            }
+        }
+        let _ = stdout.flush();
+    }
+}
+
+fn execute(console: &Console, cmd: Command) -> anyhow::Result<String> {
+    match cmd {
+        Command::Help => Ok(help()),
+        Command::Get { key } => {
+            let def = crate::settings::def(key)?;
+            if def.secret {
+                return Ok("(secret)".to_string());
+            }
+            let setting = console.setting.lock().unwrap();
+            match crate::settings::load(&setting.1, def) {
+                Some(value) => Ok(serde_json::to_string(&value)?),
+                None => Ok("(not set)".to_string()),
+            }
+        }
+        Command::Set { key, value } => {
+            let def = crate::settings::def(key)?;
+            let value = def.parse_str(value)?;
+            crate::bt::save_setting(&mut console.setting.lock().unwrap(), def, &value)?;
+            Ok("OK".to_string())
+        }
+        Command::WifiScan => {
+            let mut entries = console.improv.scan()?;
+            entries.sort_by_key(|e| std::cmp::Reverse(e.rssi));
+            Ok(entries
+                .iter()
+                .map(|e| format!("{:>4} dBm  auth {:<3}  {}\n", e.rssi, e.auth, e.ssid))
+                .collect())
+        }
+        Command::Status => {
+            let setting = console.setting.lock().unwrap();
+            Ok(String::from_utf8(crate::bt::device_status(&setting.0))?)
+        }
+        Command::Heap => {
+            let (internal, spiram) = crate::free_heap();
+            Ok(format!(
+                "internal {} bytes, spiram {} bytes",
+                internal, spiram
+            ))
+        }
+        Command::Reboot => unsafe { esp_idf_svc::sys::esp_restart() },
+        Command::FactoryReset => {
+            log::warn!("Factory reset from the console");
+            crate::reset::factory_reset(&console.assets)
+        }
+        Command::Import { bundle } => {
+            let report =
+                crate::bundle::import(&console.setting, &console.assets, bundle.as_bytes())?;
+            Ok(serde_json::to_string(&report)?)
+        }
+        Command::Export => crate::bundle::export(&console.setting),
+        Command::Play { name } => {
+            use crate::audio::AudioData;
+
+            let data = console.assets.lock().unwrap().get(name)?;
+            let (tx, _rx) = tokio::sync::oneshot::channel();
+            [AudioData::Start, AudioData::Chunk(data), AudioData::End(tx)]
+                .into_iter()
+                .try_for_each(|d| console.player.send(d))
+                .map_err(|_| anyhow::anyhow!("The audio player is not running"))?;
+            Ok(format!("Playing {}", name))
+        }
+        Command::LogLevel(None) => Ok(log::max_level().to_string()),
+        Command::LogLevel(Some(level)) => {
+            use esp_idf_svc::sys::*;
+
+            let esp_level = match level {
+                log::LevelFilter::Off => esp_log_level_t_ESP_LOG_NONE,
+                log::LevelFilter::Error => esp_log_level_t_ESP_LOG_ERROR,
+                log::LevelFilter::Warn => esp_log_level_t_ESP_LOG_WARN,
+                log::LevelFilter::Info => esp_log_level_t_ESP_LOG_INFO,
+                log::LevelFilter::Debug => esp_log_level_t_ESP_LOG_DEBUG,
+                log::LevelFilter::Trace => esp_log_level_t_ESP_LOG_VERBOSE,
+            };
+            unsafe { esp_log_level_set(b"*\0".as_ptr() as _, esp_level) };
+            log::set_max_level(level);
+            Ok(format!("Log level {}", level))
+        }
+    }
+}
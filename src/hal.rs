@@ -1,3 +1,9 @@
+/// The board variant this firmware was built for.
+#[cfg(feature = "box")]
+pub const BOARD: &str = "box";
+#[cfg(feature = "boards")]
+pub const BOARD: &str = "boards";
+
 #[cfg(feature = "box")]
 pub fn audio_init() {
     use esp_idf_svc::sys::hal_driver;
@@ -0,0 +1,371 @@
+pub use echokit_core::improv::*;
+
+/// Where clients send users who still have to set the server URL.
+const SETUP_URL: &str = "https://echokit.dev/setup/";
+
+/// Improv Wi-Fi (https://www.improv-wifi.com/), the provisioning protocol of
+/// Home Assistant and ESP Web Tools. The state is shared by the BLE and
+/// serial transports.
+pub struct Improv {
+    setting: crate::bt::SharedSetting,
+    /// The setup station, only in setup mode. Networks can't be changed
+    /// without it.
+    wifi: Option<crate::bt::SharedWifi>,
+    state: std::sync::Mutex<State>,
+    /// Set once a BLE client looks at the service, so a K0 press in setup
+    /// mode goes to it instead of restarting the device.
+    contacted: std::sync::atomic::AtomicBool,
+    /// Notifies and advertises state changes over BLE.
+    ble_report: std::sync::Mutex<Option<Box<dyn FnMut(State) + Send>>>,
+}
+
+impl Improv {
+    /// Anyone in range can write to the BLE service, so in setup mode it
+    /// starts out requiring authorization, given by a K0 press (see
+    /// [`Improv::authorize`]). The serial port needs a cable, which is
+    /// authorization enough.
+    pub fn new(setting: crate::bt::SharedSetting, wifi: Option<crate::bt::SharedWifi>) -> Self {
+        let state = if wifi.is_some() {
+            State::AuthorizationRequired
+        } else {
+            State::Provisioned
+        };
+        Self {
+            setting,
+            wifi,
+            state: std::sync::Mutex::new(state),
+            contacted: std::sync::atomic::AtomicBool::new(false),
+            ble_report: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        *self.state.lock().unwrap()
+    }
+
+    /// Authorizes a BLE client that is waiting for it. Returns false when no
+    /// client has contacted the service yet or none needs authorizing, so the
+    /// K0 press can do what it otherwise would.
+    pub fn authorize(&self) -> bool {
+        use std::sync::atomic::Ordering;
+        if !self.contacted.load(Ordering::Relaxed) {
+            return false;
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if *state != State::AuthorizationRequired {
+                return false;
+            }
+            *state = State::Authorized;
+        }
+        log::info!("Improv: authorized by K0");
+        self.ble_report(State::Authorized);
+        true
+    }
+
+    fn ble_report(&self, state: State) {
+        if let Some(report) = self.ble_report.lock().unwrap().as_mut() {
+            report(state);
+        }
+    }
+
+    fn check_authorized(&self) -> Result<(), ErrorCode> {
+        if self.state() == State::AuthorizationRequired {
+            log::warn!("Improv: press K0 to authorize first");
+            return Err(ErrorCode::NotAuthorized);
+        }
+        Ok(())
+    }
+
+    fn set_state(&self, state: State, report: &mut dyn FnMut(State)) {
+        *self.state.lock().unwrap() = state;
+        report(state);
+    }
+
+    /// Runs `rpc`, calling `report` on every state change. Returns the
+    /// results to send, several for a scan.
+    pub fn run(
+        &self,
+        rpc: Rpc,
+        report: &mut dyn FnMut(State),
+    ) -> Result<Vec<Vec<String>>, ErrorCode> {
+        log::info!("Improv RPC {:#04x}", rpc.command());
+        match rpc {
+            Rpc::WifiSettings { ssid, pass } => {
+                let Some(wifi) = &self.wifi else {
+                    log::warn!("Improv: networks can only be changed in setup mode");
+                    return Err(ErrorCode::NotAuthorized);
+                };
+                self.check_authorized()?;
+                self.set_state(State::Provisioning, report);
+                let network = crate::networks::KnownNetwork {
+                    ssid: ssid.clone(),
+                    pass: pass.clone(),
+                    ..Default::default()
+                };
+                let ip = self.setting.lock().unwrap().0.ip.clone();
+                let r = crate::network::try_join(wifi, &network, &ip, || {});
+                // stay off the network until the restart, scans need an idle station
+                let _ = wifi.lock().unwrap().disconnect();
+                if let Err(e) = r {
+                    log::error!("Improv: failed to join {}: {:?}", ssid, e);
+                    self.set_state(State::Authorized, report);
+                    return Err(ErrorCode::UnableToConnect);
+                }
+
+                let mut setting = self.setting.lock().unwrap();
+                setting.0.ssid = ssid;
+                setting.0.pass = pass;
+                if crate::bt::save_network(&mut setting).is_err() {
+                    self.set_state(State::Authorized, report);
+                    return Err(ErrorCode::Unknown);
+                }
+                let redirect = if setting.0.settings.server_url.is_empty() {
+                    vec![SETUP_URL.to_string()]
+                } else {
+                    vec![]
+                };
+                drop(setting);
+                self.set_state(State::Provisioned, report);
+                Ok(vec![redirect])
+            }
+            Rpc::Identify => {
+                // no way to identify, the capabilities say so
+                report(self.state());
+                Ok(vec![])
+            }
+            Rpc::DeviceInfo => {
+                let id = crate::network::device_id();
+                Ok(vec![vec![
+                    "EchoKit".to_string(),
+                    env!("CARGO_PKG_VERSION").to_string(),
+                    "ESP32-S3".to_string(),
+                    format!("EchoKit-{}", &id[id.len() - 4..]),
+                ]])
+            }
+            Rpc::Scan => {
+                let entries = self.scan().map_err(|e| {
+                    log::error!("Improv: wifi scan failed: {:?}", e);
+                    ErrorCode::Unknown
+                })?;
+                let mut results: Vec<_> = entries
+                    .into_iter()
+                    .map(|e| {
+                        let secured = if e.auth == 0 { "NO" } else { "YES" };
+                        vec![e.ssid, e.rssi.to_string(), secured.to_string()]
+                    })
+                    .collect();
+                // an empty result ends the list
+                results.push(vec![]);
+                Ok(results)
+            }
+            Rpc::ServerUrl(None) => {
+                let url = self.setting.lock().unwrap().0.settings.server_url.clone();
+                Ok(vec![vec![url]])
+            }
+            Rpc::ServerUrl(Some(url)) => {
+                self.check_authorized()?;
+                let def = crate::settings::def("server_url").map_err(|_| ErrorCode::Unknown)?;
+                let value = def
+                    .validate(crate::settings::Value::Text(url))
+                    .map_err(|e| {
+                        log::warn!("Improv: {}", e);
+                        ErrorCode::InvalidRpc
+                    })?;
+                crate::bt::save_setting(&mut self.setting.lock().unwrap(), def, &value)
+                    .map_err(|_| ErrorCode::Unknown)?;
+                let url = self.setting.lock().unwrap().0.settings.server_url.clone();
+                Ok(vec![vec![url]])
+            }
+        }
+    }
+
+    /// Scans with the setup station, or through the WiFi supervisor once the
+    /// device runs normally.
+    pub fn scan(&self) -> anyhow::Result<Vec<crate::networks::ScanEntry>> {
+        match &self.wifi {
+            Some(wifi) => crate::network::scan(&mut wifi.lock().unwrap()),
+            None => crate::network::supervisor_scan(),
+        }
+    }
+
+    /// Handles a packet from the serial console, writing the replies to `out`.
+    pub fn serial(&self, input: Input, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let rpc = match input {
+            Input::Text(_) => return Ok(()),
+            Input::Invalid => Err(ErrorCode::InvalidRpc),
+            Input::Packet { kind, .. } if kind != SERIAL_RPC => return Ok(()),
+            Input::Packet { data, .. } => match &data[..] {
+                [command, len, rest @ ..] if rest.len() == *len as usize => {
+                    Rpc::parse(*command, rest)
+                }
+                _ => Err(ErrorCode::InvalidRpc),
+            },
+        };
+
+        // serial Improv has no authorization step, the cable is the proof
+        {
+            let mut state = self.state.lock().unwrap();
+            if *state == State::AuthorizationRequired {
+                *state = State::Authorized;
+            }
+        }
+        let mut frames = Vec::new();
+        let mut report = |state: State| {
+            frames.push(serial_frame(SERIAL_CURRENT_STATE, &[state as u8]));
+        };
+        let r = match rpc {
+            Ok(Rpc::Identify) => {
+                report(self.state());
+                Ok(vec![])
+            }
+            Ok(rpc) => {
+                let command = rpc.command();
+                self.run(rpc, &mut report).map(|results| {
+                    results
+                        .iter()
+                        .map(|r| serial_frame(SERIAL_RPC_RESULT, &rpc_result(command, r)))
+                        .collect()
+                })
+            }
+            Err(e) => Err(e),
+        };
+        // state changes during a long RPC go out once it is done
+        for frame in frames {
+            out.write_all(&frame)?;
+        }
+        match r {
+            Ok(results) => {
+                for frame in results {
+                    out.write_all(&frame)?;
+                }
+            }
+            Err(e) => out.write_all(&serial_frame(SERIAL_ERROR_STATE, &[e as u8]))?,
+        }
+        out.flush()
+    }
+}
+
+const SERVICE_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("00467768-6228-2272-4663-277478268000");
+const STATE_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("00467768-6228-2272-4663-277478268001");
+const ERROR_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("00467768-6228-2272-4663-277478268002");
+const RPC_COMMAND_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("00467768-6228-2272-4663-277478268003");
+const RPC_RESULT_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("00467768-6228-2272-4663-277478268004");
+const CAPABILITIES_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("00467768-6228-2272-4663-277478268005");
+/// Service data UUID, advertised with the state so scanners can show it.
+const SERVICE_DATA_ID: u16 = 0x4677;
+
+/// Adds the Improv service next to the setup service. Improv clients don't
+/// pair, so none of it needs an encrypted link; credentials are only taken
+/// once K0 authorized the client.
+pub fn ble_service(
+    server: &mut esp32_nimble::BLEServer,
+    advertising: &'static esp32_nimble::utilities::mutex::Mutex<esp32_nimble::BLEAdvertising>,
+    improv: std::sync::Arc<Improv>,
+) -> anyhow::Result<()> {
+    use esp32_nimble::{utilities::BleUuid, BLEAdvertisementData, NimbleProperties};
+
+    let service = server.create_service(SERVICE_ID);
+    let read_notify = NimbleProperties::READ | NimbleProperties::NOTIFY;
+
+    let state_characteristic = service.lock().create_characteristic(STATE_ID, read_notify);
+    state_characteristic
+        .lock()
+        .set_value(&[improv.state() as u8]);
+    let state_read = state_characteristic.clone();
+    let error_characteristic = service.lock().create_characteristic(ERROR_ID, read_notify);
+    error_characteristic
+        .lock()
+        .set_value(&[ErrorCode::None as u8]);
+    let result_characteristic = service
+        .lock()
+        .create_characteristic(RPC_RESULT_ID, read_notify);
+    // no identify
+    service
+        .lock()
+        .create_characteristic(CAPABILITIES_ID, NimbleProperties::READ)
+        .lock()
+        .set_value(&[0]);
+
+    let advertise = move |state: State| {
+        let r = advertising.lock().scan_response_data(
+            BLEAdvertisementData::new()
+                .add_service_uuid(SERVICE_ID)
+                .service_data(
+                    BleUuid::from_uuid16(SERVICE_DATA_ID),
+                    &[state as u8, 0, 0, 0, 0, 0],
+                ),
+        );
+        if let Err(e) = r {
+            log::error!("Failed to advertise Improv state: {:?}", e);
+        }
+    };
+    advertise(improv.state());
+    *improv.ble_report.lock().unwrap() = Some(Box::new(move |state: State| {
+        state_characteristic
+            .lock()
+            .set_value(&[state as u8])
+            .notify();
+        advertise(state);
+    }));
+
+    let improv_read = improv.clone();
+    state_read.lock().on_read(move |c, _| {
+        improv_read
+            .contacted
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        c.set_value(&[improv_read.state() as u8]);
+    });
+
+    // RPCs can take a while (joining a network), run them off the BLE task
+    let (rpc_tx, rpc_rx) = std::sync::mpsc::channel::<Vec<u8>>();
+    let improv_rpc = improv.clone();
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            let improv = improv_rpc;
+            for packet in rpc_rx {
+                error_characteristic
+                    .lock()
+                    .set_value(&[ErrorCode::None as u8])
+                    .notify();
+                let mut report = |state: State| improv.ble_report(state);
+                let r = Rpc::parse_ble(&packet).and_then(|rpc| {
+                    let command = rpc.command();
+                    Ok((command, improv.run(rpc, &mut report)?))
+                });
+                match r {
+                    Ok((command, results)) => {
+                        for result in results {
+                            result_characteristic
+                                .lock()
+                                .set_value(&with_checksum(rpc_result(command, &result)))
+                                .notify();
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Improv RPC failed: {:?}", e);
+                        error_characteristic.lock().set_value(&[e as u8]).notify();
+                    }
+                }
+            }
+        })?;
+
+    service
+        .lock()
+        .create_characteristic(RPC_COMMAND_ID, NimbleProperties::WRITE)
+        .lock()
+        .on_write(move |args| {
+            improv
+                .contacted
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+            let _ = rpc_tx.send(args.recv_data().to_vec());
+        });
+    Ok(())
+}
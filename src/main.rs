@@ -1,22 +1,47 @@
 use std::sync::{Arc, Mutex};
 
+use echokit_core::{networks, protocol, transfer};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 
 mod app;
+mod assets;
 mod audio;
 mod bt;
+mod bundle;
+mod console;
 mod hal;
+mod improv;
 mod network;
-mod protocol;
+mod ota;
+mod portal;
+mod remote;
+mod reset;
+mod secrets;
+mod settings;
 mod ui;
 mod ws;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct Setting {
     ssid: String,
     pass: String,
-    server_url: String,
-    background_gif: (Vec<u8>, bool), // (data, ended)
+    settings: settings::Settings,
+    networks: networks::Networks,
+    ip: networks::IpSettings,
+    background_gif: (Vec<u8>, bool), // (data, verified)
+    /// Why the previous run stopped, if it failed.
+    last_error: Option<String>,
+}
+
+impl Setting {
+    /// Keeps `settings` and the audio volume in sync with a write to the
+    /// settings store.
+    fn apply(&mut self, key: &str, value: &settings::Value) {
+        self.settings.apply(key, value);
+        if key == "volume" {
+            audio::set_volume(self.settings.volume);
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -27,6 +52,20 @@ fn main() -> anyhow::Result<()> {
     let _fs = esp_idf_svc::io::vfs::MountedEventfs::mount(20)?;
     let partition = esp_idf_svc::nvs::EspDefaultNvsPartition::take()?;
     let nvs = esp_idf_svc::nvs::EspDefaultNvs::new(partition, "setting", true)?;
+    let key = secrets::device_key()
+        .map_err(|e| log::warn!("Secrets are stored unsealed: {:?}", e))
+        .ok();
+    let mut nvs = secrets::Sealed::new(settings::Nvs(nvs), key);
+    if let Err(e) = settings::migrate(&mut nvs) {
+        log::error!("Failed to migrate settings: {:?}", e);
+    }
+    if let Err(e) = nvs.seal_all() {
+        log::error!("Failed to seal secrets: {:?}", e);
+    }
+    let assets = assets::mount().unwrap_or_else(|e| {
+        log::error!("Failed to mount assets, using the built-in ones: {:?}", e);
+        assets::AssetStore::new(assets::FsStorage::new(assets::MOUNT_POINT))
+    });
 
     log_heap();
 
@@ -34,38 +73,48 @@ fn main() -> anyhow::Result<()> {
     ui::lcd_init().unwrap();
 
     log_heap();
-    let mut ssid_buf = [0; 32];
-    let ssid = nvs
-        .get_str("ssid", &mut ssid_buf)
-        .map_err(|e| log::error!("Failed to get ssid: {:?}", e))
-        .ok()
-        .flatten();
-
-    let mut pass_buf = [0; 64];
-    let pass = nvs
-        .get_str("pass", &mut pass_buf)
-        .map_err(|e| log::error!("Failed to get pass: {:?}", e))
-        .ok()
-        .flatten();
-
-    let mut server_url = [0; 128];
-    let server_url = nvs
-        .get_str("server_url", &mut server_url)
-        .map_err(|e| log::error!("Failed to get server_url: {:?}", e))
-        .ok()
-        .flatten();
-
-    // 1MB buffer for GIF
-    let mut gif_buf = vec![0; 1024 * 1024];
-    let background_gif = nvs.get_blob("background_gif", &mut gif_buf)?;
-
-    log::info!("SSID: {:?}", ssid);
-    log::info!("PASS: {:?}", pass);
-    log::info!("Server URL: {:?}", server_url);
+    let networks = networks::load(&nvs);
+    let ip = networks::load_ip(&nvs);
+    let settings = settings::Settings::load(&nvs);
+
+    // a background that doesn't decode would fail every boot before K0 is
+    // checked, so not even a factory reset could get past it
+    let background_gif = assets
+        .active(protocol::AssetKind::Background)
+        .filter(|gif| match ui::check_gif(gif) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Stored background can't be shown, using none: {:?}", e);
+                false
+            }
+        });
+    let hello_audio = assets
+        .active(protocol::AssetKind::Hello)
+        .unwrap_or_else(|| audio::WAKE_WAV.to_vec());
+    let assets = Arc::new(Mutex::new(assets));
+
+    log::info!("Networks: {}", networks.summary_json());
+    log::info!("IP settings: {:?}", ip);
+    log::info!("Settings: {:?}", settings);
+
+    let last_error = match settings::Storage::get(&nvs, LAST_ERROR_KEY, LAST_ERROR_KIND) {
+        Ok(Some(settings::Value::Text(error))) => Some(error),
+        Ok(_) => None,
+        Err(e) => {
+            log::error!("Failed to get last error: {:?}", e);
+            None
+        }
+    };
+    log::info!("Last error: {:?}", last_error);
+
+    audio::set_volume(settings.volume);
+    let ble_remote = settings.ble_remote;
 
     log_heap();
-    if let Some(background_gif) = background_gif {
-        let _ = ui::backgroud(&background_gif);
+    if let Some(background_gif) = &background_gif {
+        if let Err(e) = ui::backgroud(background_gif) {
+            log::error!("Failed to show the background: {:?}", e);
+        }
     } else {
         let mut ui = ui::UI::new(None).unwrap();
         ui.text = "You can hold K0 goto setup page".to_string();
@@ -87,32 +136,94 @@ fn main() -> anyhow::Result<()> {
 
     let mut gui = ui::UI::new(None).unwrap();
 
+    // NVS, the display, audio and the button are up, which is what a broken
+    // image breaks. WiFi and the server fail for reasons of their own, and a
+    // device in setup mode never reaches them, so they don't count.
+    ota::mark_boot_successful();
+
+    let top_network = networks.list().first().cloned().unwrap_or_default();
     let setting = Arc::new(Mutex::new((
         Setting {
-            ssid: ssid.unwrap_or_default().to_string(),
-            pass: pass.unwrap_or_default().to_string(),
-            server_url: server_url.unwrap_or_default().to_string(),
-            background_gif: (Vec::with_capacity(1024 * 1024), false), // 1MB
+            ssid: top_network.ssid,
+            pass: top_network.pass,
+            settings,
+            networks,
+            ip,
+            background_gif: (Vec::new(), false),
+            last_error,
         },
         nvs,
     )));
 
     log_heap();
 
+    let (tx1, rx1) = tokio::sync::mpsc::unbounded_channel();
+    let start_console = |improv: Arc<improv::Improv>| {
+        let console = console::Console {
+            setting: setting.clone(),
+            assets: assets.clone(),
+            player: tx1.clone(),
+            improv,
+        };
+        if let Err(e) = console::start(console) {
+            log::error!("Failed to start console: {:?}", e);
+        }
+    };
+
+    // K0 held at boot opens the setup page, held for `reset::HOLD` longer it
+    // resets the device
+    let button_held = button.is_low();
+    if button_held {
+        gui.state = "Release K0 to set up the device".to_string();
+        gui.text = format!(
+            "Keep holding K0 for {} seconds to factory reset.\n\
+             This clears all settings, WiFi networks and custom assets.",
+            reset::HOLD.as_secs()
+        );
+        gui.display_flush().unwrap();
+        let start = std::time::Instant::now();
+        while button.is_low() && start.elapsed() < reset::HOLD {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        if button.is_low() {
+            reset::factory_reset(&assets);
+        }
+    }
+
     let need_init = {
         let setting = setting.lock().unwrap();
-        setting.0.ssid.is_empty()
-            || setting.0.pass.is_empty()
-            || setting.0.server_url.is_empty()
-            || button.is_low()
+        setting.0.networks.is_empty() || setting.0.settings.server_url.is_empty() || button_held
     };
     if need_init {
-        bt::bt(setting.clone()).unwrap();
+        // nothing plays in setup mode, the console says so
+        drop(rx1);
+        let wifi = network::setup_wifi(peripherals.modem, sysloop.clone())?;
+        let wifi = Arc::new(Mutex::new(wifi));
+        let improv = Arc::new(improv::Improv::new(setting.clone(), Some(wifi.clone())));
+        start_console(improv.clone());
+        let passkey =
+            bt::bt(setting.clone(), wifi.clone(), assets.clone(), improv.clone()).unwrap();
+        // for browsers without Web Bluetooth
+        let portal = portal::start(setting.clone(), assets.clone(), &wifi)
+            .map_err(|e| log::error!("Failed to start setup portal: {:?}", e))
+            .ok();
         log_heap();
 
         gui.state = "Please setup device by bt".to_string();
-        gui.text = "Goto https://echokit.dev/setup/ to set up the device.\nPress K0 to continue"
-            .to_string();
+        let portal_text = portal
+            .as_ref()
+            .map(|p| {
+                format!(
+                    "Or join WiFi {} ({}) and open http://{}/\n",
+                    p.ssid, p.password, p.ip
+                )
+            })
+            .unwrap_or_default();
+        gui.text = format!(
+            "Goto https://echokit.dev/setup/ to set up the device.\n\
+             Pairing code: {:06}\n{}Press K0 to continue, or to authorize Improv",
+            passkey, portal_text
+        );
         gui.display_qrcode("https://echokit.dev/setup/").unwrap();
 
         #[cfg(feature = "boards")]
@@ -130,7 +241,17 @@ fn main() -> anyhow::Result<()> {
             );
         }
 
-        b.block_on(button.wait_for_falling_edge()).unwrap();
+        // a press goes to an Improv client waiting for authorization first
+        loop {
+            b.block_on(button.wait_for_falling_edge()).unwrap();
+            if !improv.authorize() {
+                break;
+            }
+            gui.text = "Improv client authorized.\nPress K0 to continue".to_string();
+            gui.display_flush().unwrap();
+            // the release must not count as the next press
+            b.block_on(button.wait_for_rising_edge()).unwrap();
+        }
         {
             let mut setting = setting.lock().unwrap();
             if setting.0.background_gif.1 {
@@ -140,25 +261,30 @@ fn main() -> anyhow::Result<()> {
                 let mut new_gif = Vec::new();
                 std::mem::swap(&mut setting.0.background_gif.0, &mut new_gif);
 
-                let _ = ui::backgroud(&new_gif);
-                log::info!("Background GIF set from NVS");
-
-                gui.text = "Background GIF set OK".to_string();
-                gui.display_flush().unwrap();
-
-                if !new_gif.is_empty() {
-                    setting
-                        .1
-                        .set_blob("background_gif", &new_gif)
-                        .map_err(|e| log::error!("Failed to save background GIF to NVS: {:?}", e))
-                        .unwrap();
-                    log::info!("Background GIF saved to NVS");
+                let r = assets::save_background(
+                    &mut *assets.lock().unwrap(),
+                    assets::DEFAULT_BACKGROUND,
+                    &new_gif,
+                );
+                match r {
+                    Ok(()) => {
+                        if let Err(e) = ui::backgroud(&new_gif) {
+                            log::error!("Failed to show the background: {:?}", e);
+                        }
+                        gui.text = "Background GIF set OK".to_string();
+                    }
+                    Err(e) => {
+                        log::error!("Failed to save background GIF: {:?}", e);
+                        gui.text = format!("Background GIF not saved: {}", e);
+                    }
                 }
+                gui.display_flush().unwrap();
             }
         }
 
         unsafe { esp_idf_svc::sys::esp_restart() }
     }
+    start_console(Arc::new(improv::Improv::new(setting.clone(), None)));
 
     gui.state = "Connecting to wifi...".to_string();
     gui.text.clear();
@@ -167,13 +293,14 @@ fn main() -> anyhow::Result<()> {
     let _wifi = {
         let setting = setting.lock().unwrap();
         network::wifi(
-            &setting.0.ssid,
-            &setting.0.pass,
+            &setting.0.networks,
+            &setting.0.ip,
             peripherals.modem,
             sysloop.clone(),
         )
     };
-    if _wifi.is_err() {
+    if let Err(e) = &_wifi {
+        save_last_error(&setting, &format!("Failed to connect to wifi: {}", e));
         gui.state = "Failed to connect to wifi".to_string();
         gui.text = "Press K0 to restart".to_string();
         gui.display_flush().unwrap();
@@ -184,14 +311,22 @@ fn main() -> anyhow::Result<()> {
     let wifi = _wifi.unwrap();
     log_heap();
 
-    let mac = wifi.ap_netif().get_mac().unwrap();
-    let mac_str = format!(
-        "{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
-        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
-    );
+    let mac_str = network::device_id();
+    let (networks, ip) = {
+        let setting = setting.lock().unwrap();
+        (setting.0.networks.clone(), setting.0.ip.clone())
+    };
+    let wifi_state = network::supervise(wifi, sysloop.clone(), networks, ip)?;
 
     let (evt_tx, evt_rx) = tokio::sync::mpsc::channel(64);
-    let (tx1, rx1) = tokio::sync::mpsc::unbounded_channel();
+
+    let remote = if ble_remote {
+        remote::start(evt_tx.clone(), setting.clone())
+            .map_err(|e| log::error!("Failed to start BLE remote: {:?}", e))
+            .ok()
+    } else {
+        None
+    };
 
     // 创建AFE实例
     let afe_handle = std::sync::Arc::new(audio::AFE::new());
@@ -212,6 +347,7 @@ fn main() -> anyhow::Result<()> {
             ws.into(),
             (evt_tx.clone(), rx1),
             afe_handle_clone.clone(), // 传递AFE实例
+            hello_audio,
         )
     };
 
@@ -235,6 +371,7 @@ fn main() -> anyhow::Result<()> {
             dout.into(),
             (evt_tx.clone(), rx1),
             afe_handle_clone.clone(), // 传递AFE实例
+            hello_audio,
         )
     };
 
@@ -244,12 +381,14 @@ fn main() -> anyhow::Result<()> {
 
     log_heap();
 
-    let server_url = {
+    let (server_url, settings) = {
         let setting = setting.lock().unwrap();
-        format!("{}{}", setting.0.server_url, mac_str)
+        let url = format!("{}{}", setting.0.settings.server_url, mac_str);
+        (url, setting.0.settings.clone())
     };
-    let server = b.block_on(ws::Server::new(server_url.clone()));
-    if server.is_err() {
+    let server = b.block_on(ws::Server::new(server_url.clone(), &settings));
+    if let Err(e) = &server {
+        save_last_error(&setting, &format!("Failed to connect to server: {}", e));
         gui.state = "Failed to connect to server".to_string();
         gui.text = format!("Please check your server URL: {server_url}");
         gui.display_flush().unwrap();
@@ -259,12 +398,24 @@ fn main() -> anyhow::Result<()> {
 
     let server = server.unwrap();
 
-    let ws_task = app::main_work(server, tx1, evt_rx, background_gif, afe_handle);
+    let ws_task = app::main_work(
+        server,
+        tx1,
+        evt_rx,
+        wifi_state,
+        assets.clone(),
+        background_gif.as_deref(),
+        afe_handle,
+        remote.clone(),
+    );
 
     b.spawn(async move {
         loop {
             let _ = button.wait_for_falling_edge().await;
             log::info!("Button k0 pressed {:?}", button.get_level());
+            if let Some(remote) = &remote {
+                remote.allow_pairing();
+            }
 
             let r = tokio::time::timeout(
                 std::time::Duration::from_secs(1),
@@ -301,6 +452,7 @@ fn main() -> anyhow::Result<()> {
         let r = ws_task.await;
         if let Err(e) = r {
             log::error!("Error: {:?}", e);
+            save_last_error(&setting, &e.to_string());
         } else {
             log::info!("WebSocket task finished successfully");
         }
@@ -309,17 +461,45 @@ fn main() -> anyhow::Result<()> {
     unsafe { esp_idf_svc::sys::esp_restart() }
 }
 
-pub fn log_heap() {
-    unsafe {
-        use esp_idf_svc::sys::{heap_caps_get_free_size, MALLOC_CAP_INTERNAL, MALLOC_CAP_SPIRAM};
+const LAST_ERROR_KEY: &str = "last_error";
+const LAST_ERROR_MAX_LEN: usize = 200;
+const LAST_ERROR_KIND: settings::Kind = settings::Kind::Text {
+    max_len: LAST_ERROR_MAX_LEN,
+};
+
+/// Keeps the error across the restart that follows it, it is reported by the
+/// BLE status characteristic.
+fn save_last_error(setting: &Mutex<(Setting, secrets::SealedNvs)>, error: &str) {
+    let mut error = error.to_string();
+    if error.len() > LAST_ERROR_MAX_LEN {
+        let end = (0..=LAST_ERROR_MAX_LEN)
+            .rev()
+            .find(|i| error.is_char_boundary(*i))
+            .unwrap_or(0);
+        error.truncate(end);
+    }
+    let mut setting = setting.lock().unwrap();
+    let value = settings::Value::Text(error.clone());
+    if let Err(e) = settings::Storage::set(&mut setting.1, LAST_ERROR_KEY, &value) {
+        log::error!("Failed to save last error: {:?}", e);
+    }
+    setting.0.last_error = Some(error);
+}
 
-        log::info!(
-            "Free SPIRAM heap size: {}",
-            heap_caps_get_free_size(MALLOC_CAP_SPIRAM)
-        );
-        log::info!(
-            "Free INTERNAL heap size: {}",
-            heap_caps_get_free_size(MALLOC_CAP_INTERNAL)
-        );
+/// `(internal, spiram)` free heap in bytes.
+pub fn free_heap() -> (usize, usize) {
+    use esp_idf_svc::sys::{heap_caps_get_free_size, MALLOC_CAP_INTERNAL, MALLOC_CAP_SPIRAM};
+
+    unsafe {
+        (
+            heap_caps_get_free_size(MALLOC_CAP_INTERNAL),
+            heap_caps_get_free_size(MALLOC_CAP_SPIRAM),
+        )
     }
 }
+
+pub fn log_heap() {
+    let (internal, spiram) = free_heap();
+    log::info!("Free SPIRAM heap size: {}", spiram);
+    log::info!("Free INTERNAL heap size: {}", internal);
+}
@@ -1,60 +1,618 @@
+use std::time::{Duration, Instant};
+
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::peripheral,
     http::{client::EspHttpConnection, Method},
-    wifi::{AuthMethod, BlockingWifi, EspWifi},
+    ipv4,
+    netif::{EspNetif, IpEvent, NetifConfiguration, NetifStack},
+    wifi::{
+        AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, Configuration,
+        EspWifi, PmfConfiguration, ScanMethod, ScanSortMethod, WifiDriver, WifiEvent,
+    },
 };
 use log::info;
 
+use crate::networks::{IpSettings, KnownNetwork, Networks, ScanEntry, WifiAuth};
+
+/// Connects to the first saved network that works, trying the strongest
+/// visible one first.
 pub fn wifi(
-    ssid: &str,
-    pass: &str,
+    networks: &Networks,
+    ip: &IpSettings,
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
 ) -> anyhow::Result<Box<EspWifi<'static>>> {
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
+    if networks.is_empty() {
         anyhow::bail!("Missing WiFi name")
     }
-    if pass.is_empty() {
-        auth_method = AuthMethod::None;
-        info!("Wifi password is empty");
-    }
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
+    let driver = WifiDriver::new(modem, sysloop.clone(), None)?;
+    let mut esp_wifi = EspWifi::wrap_all(driver, sta_netif(ip)?, EspNetif::new(NetifStack::Ap)?)?;
 
     let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
 
-    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
-        esp_idf_svc::wifi::ClientConfiguration {
-            ssid: ssid
-                .try_into()
-                .expect("Could not parse the given SSID into WiFi config"),
-            password: pass
-                .try_into()
-                .expect("Could not parse the given password into WiFi config"),
-            auth_method,
-            ..Default::default()
-        },
-    ))?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
 
     wifi.start()?;
 
-    info!("Connecting wifi...");
+    let aps = wifi.scan().unwrap_or_else(|e| {
+        log::warn!("Wifi scan failed: {:?}", e);
+        vec![]
+    });
+    let visible: Vec<_> = aps
+        .iter()
+        .map(|ap| (ap.ssid.as_str(), ap.signal_strength))
+        .collect();
+
+    let mut connected = false;
+    for network in networks.candidates(&visible) {
+        info!("Connecting wifi {}...", network.ssid);
+        match connect(&mut wifi, network) {
+            Ok(()) => {
+                connected = true;
+                break;
+            }
+            Err(e) => {
+                log::warn!("Failed to connect to {}: {:?}", network.ssid, e);
+                let _ = wifi.disconnect();
+            }
+        }
+    }
+    if !connected {
+        anyhow::bail!("Failed to connect to any saved WiFi network");
+    }
+    apply_dns(wifi.wifi_mut(), ip);
+
+    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+
+    info!("Wifi DHCP info: {:?}", ip_info);
+
+    Ok(Box::new(esp_wifi))
+}
+
+/// Starts the station without joining a network, for scanning while the
+/// device is being set up.
+pub fn setup_wifi(
+    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+) -> anyhow::Result<Box<EspWifi<'static>>> {
+    let driver = WifiDriver::new(modem, sysloop, None)?;
+    let mut wifi = EspWifi::wrap_all(
+        driver,
+        sta_netif(&IpSettings::default())?,
+        EspNetif::new(NetifStack::Ap)?,
+    )?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi.start()?;
+    Ok(Box::new(wifi))
+}
+
+/// Adds an access point next to the setup station for the setup portal.
+/// Returns the address of the device on it.
+pub fn start_ap(
+    wifi: &mut EspWifi<'static>,
+    ssid: &str,
+    password: &str,
+) -> anyhow::Result<ipv4::Ipv4Addr> {
+    let client = match wifi.get_configuration()? {
+        Configuration::Client(c) | Configuration::Mixed(c, _) => c,
+        _ => ClientConfiguration::default(),
+    };
+    let ap = AccessPointConfiguration {
+        ssid: ssid
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("AP SSID is too long"))?,
+        password: password
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("AP password is too long"))?,
+        auth_method: AuthMethod::WPA2Personal,
+        max_connections: 4,
+        ..Default::default()
+    };
+    wifi.set_configuration(&Configuration::Mixed(client, ap))?;
+    Ok(wifi.ap_netif().get_ip_info()?.ip)
+}
+
+/// IP settings of the station netif, it is only rebuilt when they change.
+/// The setup station starts with the defaults.
+static STA_IP: std::sync::Mutex<Option<IpSettings>> = std::sync::Mutex::new(None);
+/// One join at a time, from BLE, the setup portal or Improv.
+static JOINING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Joins `network` and waits for an address, for trying credentials during
+/// setup. `on_associated` runs once the AP accepted them.
+///
+/// `wifi` is only locked for each step, so scans and the setup portal aren't
+/// held up while the AP answers. The driver is only restarted, dropping the
+/// portal clients, when the IP settings need a new station netif.
+pub fn try_join(
+    wifi: &std::sync::Mutex<Box<EspWifi<'static>>>,
+    network: &KnownNetwork,
+    ip: &IpSettings,
+    on_associated: impl FnOnce(),
+) -> anyhow::Result<ipv4::IpInfo> {
+    const TIMEOUT: Duration = Duration::from_secs(20);
+
+    let _joining = JOINING.lock().unwrap();
+    {
+        let mut wifi = wifi.lock().unwrap();
+        let _ = wifi.disconnect();
+        let mut sta_ip = STA_IP.lock().unwrap();
+        if sta_ip.as_ref().unwrap_or(&IpSettings::default()) != ip {
+            wifi.stop()?;
+            wifi.swap_netif_sta(sta_netif(ip)?)?;
+            *sta_ip = Some(ip.clone());
+        }
+        let config = match wifi.get_configuration()? {
+            Configuration::Mixed(_, ap) => Configuration::Mixed(client_config(network)?, ap),
+            _ => Configuration::Client(client_config(network)?),
+        };
+        wifi.set_configuration(&config)?;
+        if !wifi.is_started()? {
+            wifi.start()?;
+        }
+        wifi.connect()?;
+    }
+
+    let deadline = Instant::now() + TIMEOUT;
+    while !wifi.lock().unwrap().is_connected()? {
+        if Instant::now() > deadline {
+            anyhow::bail!("Could not join {}, check the password", network.ssid);
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    on_associated();
+
+    while !wifi.lock().unwrap().is_up()? {
+        if Instant::now() > deadline {
+            anyhow::bail!("No IP address from {}", network.ssid);
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    let mut wifi = wifi.lock().unwrap();
+    apply_dns(&mut wifi, ip);
+    Ok(wifi.sta_netif().get_ip_info()?)
+}
+
+/// Scans for access points, blocking until the scan is done.
+pub fn scan(wifi: &mut EspWifi<'static>) -> anyhow::Result<Vec<ScanEntry>> {
+    let entries = wifi
+        .scan()?
+        .into_iter()
+        .map(|ap| ScanEntry {
+            ssid: ap.ssid.to_string(),
+            rssi: ap.signal_strength,
+            auth: match ap.auth_method {
+                Some(AuthMethod::None) => 0,
+                Some(AuthMethod::WEP) => 1,
+                Some(AuthMethod::WPA) => 2,
+                Some(AuthMethod::WPA2Personal) => 3,
+                Some(AuthMethod::WPAWPA2Personal) => 4,
+                Some(AuthMethod::WPA2Enterprise) => 5,
+                Some(AuthMethod::WPA3Personal) => 6,
+                Some(AuthMethod::WPA2WPA3Personal) => 7,
+                Some(AuthMethod::WAPIPersonal) => 8,
+                _ => 255,
+            },
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Like [`scan`], for callers without the `EspWifi` handle, which the
+/// supervisor owns once the device is connected. The scan runs on the
+/// supervisor thread, so it can't overlap one of its own.
+pub fn supervisor_scan() -> anyhow::Result<Vec<ScanEntry>> {
+    let requests = SUPERVISOR
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("WiFi is not connected yet"))?;
+    let (reply_tx, reply_rx) = std::sync::mpsc::channel();
+    requests
+        .send(Request::Scan(reply_tx))
+        .map_err(|_| anyhow::anyhow!("The WiFi supervisor is not running"))?;
+    reply_rx
+        .recv()
+        .map_err(|_| anyhow::anyhow!("The WiFi supervisor is not running"))?
+}
+
+/// Roaming scans take the radio off channel for a few seconds, which stalls
+/// audio. They are put off while this is set.
+pub fn pause_roaming(paused: bool) {
+    ROAMING_PAUSED.store(paused, std::sync::atomic::Ordering::Relaxed);
+}
+
+fn connect(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+    network: &KnownNetwork,
+) -> anyhow::Result<()> {
+    wifi.set_configuration(&Configuration::Client(client_config(network)?))?;
 
     wifi.connect()?;
 
-    info!("Waiting for DHCP lease...");
+    info!("Waiting for IP...");
 
     wifi.wait_netif_up()?;
+    Ok(())
+}
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+fn sta_netif(ip: &IpSettings) -> anyhow::Result<EspNetif> {
+    let ip_configuration = match &ip.static_ip {
+        Some(s) => ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+            ip: s.ip,
+            subnet: ipv4::Subnet {
+                gateway: s.gateway,
+                mask: ipv4::Mask(s.prefix_len()),
+            },
+            dns: ip.dns,
+            secondary_dns: None,
+        }),
+        None => ipv4::ClientConfiguration::DHCP(Default::default()),
+    };
+    let mut netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: Some(ipv4::Configuration::Client(ip_configuration)),
+        ..NetifConfiguration::wifi_default_client()
+    })?;
 
-    info!("Wifi DHCP info: {:?}", ip_info);
+    let hostname = ip.hostname.clone().unwrap_or_else(default_hostname);
+    info!("Hostname: {}", hostname);
+    netif.set_hostname(&hostname)?;
+    Ok(netif)
+}
 
-    Ok(Box::new(esp_wifi))
+/// The soft-AP MAC as hex, which the server URL is built from.
+pub fn device_id() -> String {
+    use esp_idf_svc::sys::*;
+
+    let mut mac = [0u8; 6];
+    unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_SOFTAP) };
+    mac.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn default_hostname() -> String {
+    format!("echokit-{}", device_id())
+}
+
+/// Puts the configured DNS server in place of the one DHCP handed out. With a
+/// static IP it is part of the netif configuration already.
+fn apply_dns(wifi: &mut EspWifi<'static>, ip: &IpSettings) {
+    if let (None, Some(dns)) = (&ip.static_ip, ip.dns) {
+        info!("Using DNS server {}", dns);
+        wifi.sta_netif_mut().set_dns(dns);
+    }
+}
+
+fn client_config(network: &KnownNetwork) -> anyhow::Result<ClientConfiguration> {
+    // auth_method is the weakest mode accepted, WPA2 also joins WPA3 APs
+    let auth_method = match network.auth {
+        WifiAuth::Auto if network.pass.is_empty() => AuthMethod::None,
+        WifiAuth::Open => AuthMethod::None,
+        WifiAuth::Auto | WifiAuth::Wpa2Personal => AuthMethod::WPA2Personal,
+        WifiAuth::Wpa3Personal => AuthMethod::WPA3Personal,
+        WifiAuth::Wpa2Enterprise => AuthMethod::WPA2Enterprise,
+    };
+    if auth_method == AuthMethod::None {
+        info!("Wifi password is empty");
+    }
+    set_enterprise(network)?;
+
+    let mut conf = ClientConfiguration {
+        ssid: network
+            .ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid SSID: {}", network.ssid))?,
+        auth_method,
+        ..Default::default()
+    };
+    if network.auth != WifiAuth::Wpa2Enterprise {
+        conf.password = network
+            .pass
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid WiFi password"))?;
+    }
+    if network.auth == WifiAuth::Wpa3Personal {
+        conf.pmf_cfg = PmfConfiguration::Capable { required: true };
+    }
+    if network.hidden {
+        // probe every channel instead of taking the first AP that answers
+        conf.scan_method = ScanMethod::CompleteScan(ScanSortMethod::Signal);
+    }
+    Ok(conf)
+}
+
+/// The CA certificate handed to the EAP client, which keeps the pointer
+/// rather than a copy. NUL terminated, as mbedTLS wants PEM.
+static CA_CERT: std::sync::Mutex<Vec<u8>> = std::sync::Mutex::new(Vec::new());
+
+/// EAP credentials live outside `ClientConfiguration`, so they are set, or
+/// cleared, before every connect.
+fn set_enterprise(network: &KnownNetwork) -> anyhow::Result<()> {
+    use esp_idf_svc::sys::*;
+
+    let mut ca_cert = CA_CERT.lock().unwrap();
+    unsafe { esp_eap_client_clear_ca_cert() };
+    ca_cert.clear();
+    if network.auth != WifiAuth::Wpa2Enterprise {
+        esp!(unsafe { esp_wifi_sta_enterprise_disable() })?;
+        return Ok(());
+    }
+
+    let identity = if network.identity.is_empty() {
+        &network.username
+    } else {
+        &network.identity
+    };
+    unsafe {
+        esp!(esp_eap_client_set_identity(
+            identity.as_ptr(),
+            identity.len() as _
+        ))?;
+        esp!(esp_eap_client_set_username(
+            network.username.as_ptr(),
+            network.username.len() as _
+        ))?;
+        esp!(esp_eap_client_set_password(
+            network.pass.as_ptr(),
+            network.pass.len() as _
+        ))?;
+    }
+    if network.ca_cert.is_empty() {
+        log::warn!(
+            "No CA certificate for {}, its server isn't verified",
+            network.ssid
+        );
+    } else {
+        ca_cert.extend_from_slice(network.ca_cert.as_bytes());
+        ca_cert.push(0);
+        unsafe {
+            esp!(esp_eap_client_set_ca_cert(
+                ca_cert.as_ptr(),
+                ca_cert.len() as _
+            ))?;
+            // the clock isn't set before the first network is joined
+            esp!(esp_eap_client_set_disable_time_check(true))?;
+        }
+    }
+    esp!(unsafe { esp_wifi_sta_enterprise_enable() })?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WifiState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+pub type WifiStateRx = tokio::sync::watch::Receiver<WifiState>;
+
+/// What the supervisor thread is sent.
+enum Request {
+    /// From the WiFi and IP event handlers.
+    State(WifiState),
+    /// A scan for [`supervisor_scan`].
+    Scan(std::sync::mpsc::Sender<anyhow::Result<Vec<ScanEntry>>>),
+}
+
+static SUPERVISOR: std::sync::OnceLock<std::sync::mpsc::Sender<Request>> =
+    std::sync::OnceLock::new();
+static ROAMING_PAUSED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const ROAM_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// Only look for a better AP below this, and only move for a clear gain.
+const ROAM_RSSI_THRESHOLD: i8 = -70;
+const ROAM_RSSI_GAIN: i8 = 8;
+
+/// Keeps the station connected from a background thread: reconnects with
+/// backoff when the AP drops and roams to a stronger AP of the same SSID.
+///
+/// `wifi` must already be connected, as returned by [`wifi`].
+pub fn supervise(
+    wifi: Box<EspWifi<'static>>,
+    sysloop: EspSystemEventLoop,
+    networks: Networks,
+    ip: IpSettings,
+) -> anyhow::Result<WifiStateRx> {
+    let (state_tx, state_rx) = tokio::sync::watch::channel(WifiState::Connected);
+    let (evt_tx, evt_rx) = std::sync::mpsc::channel();
+    if SUPERVISOR.set(evt_tx.clone()).is_err() {
+        anyhow::bail!("The WiFi supervisor is already running");
+    }
+
+    let tx = evt_tx.clone();
+    // no DHCP event with a static IP, being associated is all there is
+    let static_ip = ip.static_ip.is_some();
+    let wifi_sub = sysloop.subscribe::<WifiEvent, _>(move |event| match event {
+        WifiEvent::StaDisconnected(..) => {
+            let _ = tx.send(Request::State(WifiState::Disconnected));
+        }
+        WifiEvent::StaConnected(..) if static_ip => {
+            let _ = tx.send(Request::State(WifiState::Connected));
+        }
+        _ => {}
+    })?;
+    let ip_sub = sysloop.subscribe::<IpEvent, _>(move |event| {
+        if let IpEvent::DhcpIpAssigned(_) = event {
+            let _ = evt_tx.send(Request::State(WifiState::Connected));
+        }
+    })?;
+
+    std::thread::Builder::new()
+        .name("wifi".to_string())
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            let _subs = (wifi_sub, ip_sub);
+            supervisor_loop(wifi, networks, ip, evt_rx, state_tx)
+        })?;
+
+    Ok(state_rx)
+}
+
+fn supervisor_loop(
+    mut wifi: Box<EspWifi<'static>>,
+    networks: Networks,
+    ip: IpSettings,
+    evt_rx: std::sync::mpsc::Receiver<Request>,
+    state_tx: tokio::sync::watch::Sender<WifiState>,
+) {
+    let mut state = WifiState::Connected;
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    let mut next_attempt = Instant::now();
+    let mut next_roam_check = Instant::now() + ROAM_CHECK_INTERVAL;
+
+    loop {
+        let deadline = match state {
+            WifiState::Connected => next_roam_check,
+            WifiState::Connecting => next_attempt,
+            WifiState::Disconnected => Instant::now(),
+        };
+        match evt_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Request::Scan(reply)) => {
+                let _ = reply.send(scan(&mut wifi));
+            }
+            Ok(Request::State(WifiState::Disconnected)) if state == WifiState::Connecting => {
+                // a failed attempt, the retry is already scheduled
+            }
+            Ok(Request::State(new_state)) if new_state != state => {
+                info!("Wifi state {:?} -> {:?}", state, new_state);
+                if new_state == WifiState::Connected {
+                    apply_dns(&mut wifi, &ip);
+                    backoff = Duration::from_secs(1);
+                    attempt = 0;
+                    next_roam_check = Instant::now() + ROAM_CHECK_INTERVAL;
+                }
+                state = new_state;
+                state_tx.send_replace(state);
+            }
+            Ok(_) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => match state {
+                WifiState::Connected => {
+                    next_roam_check = Instant::now() + ROAM_CHECK_INTERVAL;
+                    if let Err(e) = roam(&mut wifi) {
+                        log::warn!("Wifi roaming check failed: {:?}", e);
+                    }
+                }
+                WifiState::Disconnected | WifiState::Connecting => {
+                    if state == WifiState::Disconnected {
+                        state = WifiState::Connecting;
+                        state_tx.send_replace(state);
+                    }
+                    log::info!("Reconnecting wifi, next attempt in {:?}", backoff);
+                    if let Err(e) = reconnect(&mut wifi, &networks, attempt) {
+                        log::warn!("Wifi reconnect failed: {:?}", e);
+                    }
+                    attempt += 1;
+                    next_attempt = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            },
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    log::error!("Wifi supervisor stopped");
+}
+
+/// Starts a connection to the strongest AP broadcasting our SSID.
+fn reconnect(
+    wifi: &mut EspWifi<'static>,
+    networks: &Networks,
+    attempt: usize,
+) -> anyhow::Result<()> {
+    let aps = wifi.scan().unwrap_or_else(|e| {
+        log::warn!("Wifi scan failed: {:?}", e);
+        vec![]
+    });
+    let visible: Vec<_> = aps
+        .iter()
+        .map(|ap| (ap.ssid.as_str(), ap.signal_strength))
+        .collect();
+    // Start with the strongest and move down the list on every failed attempt.
+    let candidates = networks.candidates(&visible);
+    let Some(network) = candidates.get(attempt % candidates.len().max(1)) else {
+        anyhow::bail!("No saved networks");
+    };
+
+    // Without scan results, e.g. for a hidden SSID, let the driver find the AP.
+    let best = aps
+        .iter()
+        .filter(|ap| ap.ssid.as_str() == network.ssid)
+        .max_by_key(|ap| ap.signal_strength);
+    let mut conf = client_config(network)?;
+    conf.bssid = best.map(|ap| ap.bssid);
+    conf.channel = best.map(|ap| ap.channel);
+    info!("Connecting wifi {}...", network.ssid);
+    wifi.set_configuration(&Configuration::Client(conf))?;
+    wifi.connect()?;
+    Ok(())
+}
+
+/// Drops a weak AP when a clearly stronger one of the same SSID is in range,
+/// the reconnect that follows picks the strongest.
+fn roam(wifi: &mut EspWifi<'static>) -> anyhow::Result<()> {
+    use esp_idf_svc::sys::*;
+
+    let mut ap_info = wifi_ap_record_t::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) })?;
+    if ap_info.rssi >= ROAM_RSSI_THRESHOLD {
+        return Ok(());
+    }
+    if ROAMING_PAUSED.load(std::sync::atomic::Ordering::Relaxed) {
+        info!(
+            "Weak AP ({} dBm), roaming put off while audio streams",
+            ap_info.rssi
+        );
+        return Ok(());
+    }
+
+    let conf = client_configuration(wifi)?;
+    let Some(best) = strongest_ap(wifi, &conf.ssid)? else {
+        return Ok(());
+    };
+    if best.bssid == ap_info.bssid || best.rssi < ap_info.rssi + ROAM_RSSI_GAIN {
+        return Ok(());
+    }
+
+    info!(
+        "Roaming from {:02x?} ({} dBm) to {:02x?} ({} dBm)",
+        ap_info.bssid, ap_info.rssi, best.bssid, best.rssi
+    );
+    wifi.disconnect()?;
+    Ok(())
+}
+
+fn client_configuration(
+    wifi: &EspWifi<'static>,
+) -> anyhow::Result<esp_idf_svc::wifi::ClientConfiguration> {
+    match wifi.get_configuration()? {
+        Configuration::Client(conf) | Configuration::Mixed(conf, _) => Ok(conf),
+        _ => anyhow::bail!("Wifi is not in station mode"),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ApCandidate {
+    bssid: [u8; 6],
+    channel: u8,
+    rssi: i8,
+}
+
+fn strongest_ap(wifi: &mut EspWifi<'static>, ssid: &str) -> anyhow::Result<Option<ApCandidate>> {
+    let best = wifi
+        .scan()?
+        .into_iter()
+        .filter(|ap| ap.ssid.as_str() == ssid)
+        .max_by_key(|ap| ap.signal_strength)
+        .map(|ap| ApCandidate {
+            bssid: ap.bssid,
+            channel: ap.channel,
+            rssi: ap.signal_strength,
+        });
+    Ok(best)
 }
 
-#[allow(unused)]
 pub fn http_get(url: &str) -> anyhow::Result<EspHttpConnection> {
     let configuration = esp_idf_svc::http::client::Configuration::default();
     let mut conn = EspHttpConnection::new(&configuration)?;
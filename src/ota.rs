@@ -0,0 +1,135 @@
+pub use echokit_core::ota::*;
+
+use crate::protocol::FirmwareImage;
+
+/// Writes to the next OTA slot through the ESP-IDF OTA API.
+pub struct EspFlashWriter {
+    handle: esp_idf_svc::sys::esp_ota_handle_t,
+    partition: *const esp_idf_svc::sys::esp_partition_t,
+}
+
+impl EspFlashWriter {
+    pub fn begin(size: usize) -> anyhow::Result<Self> {
+        use esp_idf_svc::sys::*;
+
+        let partition = unsafe { esp_ota_get_next_update_partition(std::ptr::null()) };
+        if partition.is_null() {
+            anyhow::bail!("No OTA partition available");
+        }
+        let slot_size = unsafe { (*partition).size } as usize;
+        if size > slot_size {
+            anyhow::bail!("Image is too large: {} > {} bytes", size, slot_size);
+        }
+
+        // Sectors are erased as the image is written, erasing the whole slot
+        // up front would block the caller for seconds.
+        let mut handle = 0;
+        esp!(unsafe {
+            esp_ota_begin(partition, OTA_WITH_SEQUENTIAL_WRITES as usize, &mut handle)
+        })?;
+        Ok(Self { handle, partition })
+    }
+}
+
+impl FlashWriter for EspFlashWriter {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        use esp_idf_svc::sys::*;
+        esp!(unsafe { esp_ota_write(self.handle, data.as_ptr() as *const _, data.len()) })?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        use esp_idf_svc::sys::*;
+        esp!(unsafe { esp_ota_end(self.handle) })?;
+        esp!(unsafe { esp_ota_set_boot_partition(self.partition) })?;
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        unsafe { esp_idf_svc::sys::esp_ota_abort(self.handle) };
+    }
+}
+
+/// Checks an image, offered over the socket or to download, against the
+/// running firmware and the device's readiness.
+pub fn check_update(image: &FirmwareImage, readiness: Readiness) -> anyhow::Result<()> {
+    readiness.check()?;
+    let current = env!("CARGO_PKG_VERSION");
+    if !is_newer(current, &image.version) {
+        anyhow::bail!("Version {} is not newer than {}", image.version, current);
+    }
+    Ok(())
+}
+
+/// Checks an image offered over the socket with `check_update`, and opens
+/// the next OTA slot for it.
+pub fn accept_offer(
+    image: FirmwareImage,
+    readiness: Readiness,
+) -> anyhow::Result<Updater<EspFlashWriter>> {
+    check_update(&image, readiness)?;
+    Updater::new(image, EspFlashWriter::begin)
+}
+
+/// Whether the last reset was a brownout, see `Readiness::brownout`.
+pub fn brownout_reset() -> bool {
+    use esp_idf_svc::sys::*;
+    unsafe { esp_reset_reason() == esp_reset_reason_t_ESP_RST_BROWNOUT }
+}
+
+/// Downloads the image with `network::http_get` into the next OTA slot.
+/// Blocking, run it on its own thread.
+pub fn download(
+    url: &str,
+    image: FirmwareImage,
+    mut progress: impl FnMut(&OtaState),
+) -> anyhow::Result<()> {
+    let mut conn = crate::network::http_get(url)?;
+    if conn.status() != 200 {
+        anyhow::bail!("Firmware download failed: HTTP {}", conn.status());
+    }
+
+    let mut updater = Updater::new(image, EspFlashWriter::begin)?;
+    progress(updater.state());
+
+    let mut buf = vec![0; 4096];
+    let mut reported = 0;
+    loop {
+        let n = match conn.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                updater.abort();
+                anyhow::bail!("Firmware download failed: {:?}", e);
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        updater.write(&buf[..n])?;
+        if updater.written() - reported >= 64 * 1024 {
+            reported = updater.written();
+            progress(updater.state());
+        }
+    }
+
+    progress(&OtaState::Verifying);
+    updater.finish()?;
+    progress(updater.state());
+    Ok(())
+}
+
+/// Confirms the running image after an update, which cancels the automatic
+/// rollback done by the bootloader if we restart before getting here.
+pub fn mark_boot_successful() {
+    use esp_idf_svc::sys::*;
+
+    let mut state = 0;
+    let running = unsafe { esp_ota_get_running_partition() };
+    let r = unsafe { esp_ota_get_state_partition(running, &mut state) };
+    if r == ESP_OK && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY {
+        log::info!("First boot of new firmware, marking it valid");
+        if let Err(e) = esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() }) {
+            log::error!("Failed to mark firmware valid: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+pub use echokit_core::portal::*;
+
+use std::net::Ipv4Addr;
+
+use crate::settings;
+
+const INDEX_HTML: &str = include_str!("../assets/index.html");
+const MAX_FORM_LEN: usize = 1024;
+
+/// The setup portal, served while the device is in setup mode so it can be
+/// configured from browsers without Web Bluetooth.
+pub struct Portal {
+    pub ssid: String,
+    pub password: String,
+    pub ip: Ipv4Addr,
+    _http: esp_idf_svc::http::server::EspHttpServer<'static>,
+}
+
+/// Opens a WPA2 access point next to the setup station, with a DNS server
+/// that points every name at the device and an HTTP server for the form.
+/// The password is shown on the screen like the BLE pairing code.
+pub fn start(
+    setting: crate::bt::SharedSetting,
+    assets: crate::assets::SharedAssets,
+    wifi: &crate::bt::SharedWifi,
+) -> anyhow::Result<Portal> {
+    use esp_idf_svc::{
+        http::{server::EspHttpServer, Method},
+        io::{Read, Write},
+    };
+
+    let id = crate::network::device_id();
+    let ssid = format!("EchoKit-{}", &id[id.len() - 4..]);
+    let password = format!(
+        "{:08}",
+        unsafe { esp_idf_svc::sys::esp_random() } % 100_000_000
+    );
+    let ip = crate::network::start_ap(&mut wifi.lock().unwrap(), &ssid, &password)?;
+    log::info!("Setup portal on {} at http://{}/", ssid, ip);
+
+    std::thread::Builder::new()
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            if let Err(e) = dns_server(ip) {
+                log::error!("Setup portal DNS server stopped: {:?}", e);
+            }
+        })?;
+
+    let mut http = EspHttpServer::new(&esp_idf_svc::http::server::Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    http.fn_handler("/", Method::Get, |req| -> anyhow::Result<()> {
+        req.into_response(200, None, &[("Content-Type", "text/html")])?
+            .write_all(INDEX_HTML.as_bytes())?;
+        Ok(())
+    })?;
+    let setting_bundle = setting.clone();
+    http.fn_handler(
+        "/setting",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len == 0 || len > MAX_FORM_LEN {
+                req.into_status_response(413)?
+                    .write_all(b"Invalid request size")?;
+                return Ok(());
+            }
+            let mut body = vec![0; len];
+            req.read_exact(&mut body)
+                .map_err(|e| anyhow::anyhow!("Failed to read form: {:?}", e))?;
+            let (status, reply) = match save(&setting, &body) {
+                Ok(()) => (200, "Saved, press K0 on the device to restart".to_string()),
+                Err(e) => {
+                    log::warn!("Setup portal form rejected: {:?}", e);
+                    (400, e.to_string())
+                }
+            };
+            req.into_status_response(status)?
+                .write_all(reply.as_bytes())?;
+            Ok(())
+        },
+    )?;
+    // imports decode GIFs and write the flash, more than the httpd task's
+    // stack takes, so they run on their own thread like the BLE ones
+    type BundleJob = (
+        Vec<u8>,
+        std::sync::mpsc::Sender<anyhow::Result<crate::bundle::Report>>,
+    );
+    let (bundle_tx, bundle_rx) = std::sync::mpsc::channel::<BundleJob>();
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            for (data, reply) in bundle_rx {
+                let _ = reply.send(crate::bundle::import(&setting_bundle, &assets, &data));
+            }
+        })?;
+    // a configuration bundle, as uploaded or read from a QR code
+    http.fn_handler(
+        "/bundle",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len == 0 || len > crate::transfer::MAX_ASSET_SIZE as usize {
+                req.into_status_response(413)?
+                    .write_all(b"Invalid request size")?;
+                return Ok(());
+            }
+            let mut body = vec![0; len];
+            req.read_exact(&mut body)
+                .map_err(|e| anyhow::anyhow!("Failed to read bundle: {:?}", e))?;
+            let (reply_tx, reply_rx) = std::sync::mpsc::channel();
+            bundle_tx
+                .send((body, reply_tx))
+                .map_err(|_| anyhow::anyhow!("Bundle import thread is gone"))?;
+            let r = reply_rx
+                .recv()
+                .map_err(|_| anyhow::anyhow!("Bundle import thread is gone"))?;
+            let (status, reply) = match r {
+                Ok(report) => (200, serde_json::to_string(&report)?),
+                Err(e) => {
+                    log::warn!("Setup portal bundle rejected: {:?}", e);
+                    (400, e.to_string())
+                }
+            };
+            req.into_status_response(status)?
+                .write_all(reply.as_bytes())?;
+            Ok(())
+        },
+    )?;
+    // anything else is a connectivity check or a page the client wanted
+    // before it noticed the portal
+    let location = format!("http://{}/", ip);
+    http.fn_handler("/*", Method::Get, move |req| -> anyhow::Result<()> {
+        req.into_response(302, Some("Found"), &[("Location", location.as_str())])?;
+        Ok(())
+    })?;
+
+    Ok(Portal {
+        ssid,
+        password,
+        ip,
+        _http: http,
+    })
+}
+
+/// Stores the form through the same path as the BLE characteristics.
+fn save(setting: &crate::bt::SharedSetting, body: &[u8]) -> anyhow::Result<()> {
+    let form: Form = serde_json::from_slice(body)?;
+    let server_url = form.validate()?;
+
+    let mut setting = setting.lock().unwrap();
+    setting.0.ssid = form.ssid;
+    setting.0.pass = form.pass;
+    crate::bt::save_network(&mut setting)?;
+    crate::bt::save_setting(&mut setting, settings::def("server_url")?, &server_url)
+}
+
+fn dns_server(ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53))?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf)?;
+        if let Some(reply) = dns_reply(&buf[..n], ip) {
+            if let Err(e) = socket.send_to(&reply, peer) {
+                log::warn!("Failed to answer DNS query from {}: {:?}", peer, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+pub use echokit_core::remote::*;
+
+use std::sync::{Arc, Mutex};
+
+type Characteristic = Arc<esp32_nimble::utilities::mutex::Mutex<esp32_nimble::BLECharacteristic>>;
+
+/// How long a K0 press lets new remotes bond.
+const PAIRING_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Status shared between the main loop and the remote control service.
+pub struct Remote {
+    status: Mutex<Status>,
+    characteristic: Characteristic,
+    /// Until when new bonds are accepted, see [`Remote::allow_pairing`].
+    pairing_until: Mutex<Option<std::time::Instant>>,
+}
+
+impl Remote {
+    /// Lets new remotes bond for `PAIRING_WINDOW`. Called on a K0 press, so
+    /// only someone holding the device can add one.
+    pub fn allow_pairing(&self) {
+        log::info!("Remote pairing open for {:?}", PAIRING_WINDOW);
+        *self.pairing_until.lock().unwrap() = Some(std::time::Instant::now() + PAIRING_WINDOW);
+    }
+
+    fn pairing_open(&self) -> bool {
+        self.pairing_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| std::time::Instant::now() < until)
+    }
+
+    pub fn set_state(&self, state: &'static str) {
+        self.update(|s| s.state = state);
+    }
+
+    pub fn set_transcript(&self, text: &str) {
+        self.update(|s| s.set_transcript(text));
+    }
+
+    /// Notifies subscribers when the status changed. Reads lock the
+    /// characteristic and then the status, so the status is let go first.
+    fn update(&self, f: impl FnOnce(&mut Status)) {
+        let data = {
+            let mut status = self.status.lock().unwrap();
+            let old = status.clone();
+            f(&mut status);
+            if *status == old {
+                return;
+            }
+            serde_json::to_vec(&*status).unwrap_or_default()
+        };
+        self.characteristic.lock().set_value(&data).notify();
+    }
+}
+
+const REMOTE_SERVICE_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("b7c4e2a0-5d19-4f83-9a6e-0c2f8d41b5e7");
+const COMMAND_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("b7c4e2a1-5d19-4f83-9a6e-0c2f8d41b5e7");
+const STATUS_ID: esp32_nimble::utilities::BleUuid =
+    esp32_nimble::uuid128!("b7c4e2a2-5d19-4f83-9a6e-0c2f8d41b5e7");
+
+/// Starts the remote control service, used while the device runs normally
+/// when the `ble_remote` setting is on. A phone app or BLE button can't show
+/// or enter a passkey, so links are encrypted with "just works" pairing only.
+/// That would let anyone in range bond, so new bonds are only kept while
+/// pairing is open after a K0 press, and push to talk needs a bond.
+pub fn start(
+    evt_tx: tokio::sync::mpsc::Sender<crate::app::Event>,
+    setting: crate::bt::SharedSetting,
+) -> anyhow::Result<Arc<Remote>> {
+    use esp32_nimble::{
+        enums::{AuthReq, SecurityIOCap},
+        BLEAdvertisementData, NimbleProperties,
+    };
+
+    let ble_device = esp32_nimble::BLEDevice::take();
+    ble_device
+        .security()
+        .set_auth(AuthReq::Bond)
+        .set_io_cap(SecurityIOCap::NoInputNoOutput);
+    let ble_addr = ble_device.get_addr()?.to_string();
+    let ble_advertising = ble_device.get_advertising();
+
+    let server = ble_device.get_server();
+    server.on_connect(|_, desc| {
+        log::info!("Remote connected: {:?}", desc);
+    });
+    server.on_disconnect(|_desc, reason| {
+        log::info!("Remote disconnected ({:?})", reason);
+    });
+    server.advertise_on_disconnect(true);
+
+    let service = server.create_service(REMOTE_SERVICE_ID);
+    let status_characteristic = service.lock().create_characteristic(
+        STATUS_ID,
+        NimbleProperties::READ | NimbleProperties::READ_ENC | NimbleProperties::NOTIFY,
+    );
+    let remote = Arc::new(Remote {
+        status: Mutex::new(Status {
+            state: "idle",
+            volume: crate::audio::volume(),
+            muted: crate::audio::is_muted(),
+            transcript: String::new(),
+        }),
+        characteristic: status_characteristic.clone(),
+        pairing_until: Mutex::new(None),
+    });
+
+    // remotes bonded before this boot were let in by an earlier K0 press
+    let bonded = Mutex::new(ble_device.bonded_addresses()?);
+    let remote_ = remote.clone();
+    server.on_authentication_complete(move |desc, result| {
+        if let Err(e) = result {
+            log::warn!("Remote pairing failed: {:?}", e);
+            return;
+        }
+        let address = desc.id_address();
+        let mut bonded = bonded.lock().unwrap();
+        if bonded.contains(&address) {
+            return;
+        }
+        if desc.bonded() && remote_.pairing_open() {
+            log::info!("Remote bonded: {:?}", address);
+            bonded.push(address);
+            return;
+        }
+        log::warn!("Remote {:?} refused, press K0 to allow pairing", address);
+        if let Err(e) = esp32_nimble::BLEDevice::take().delete_bond(&address) {
+            log::error!("Failed to delete bond: {:?}", e);
+        }
+        unsafe {
+            esp_idf_svc::sys::ble_gap_terminate(
+                desc.conn_handle(),
+                esp_idf_svc::sys::BLE_ERR_AUTH_FAIL as u8,
+            );
+        }
+    });
+
+    let remote_ = remote.clone();
+    status_characteristic.lock().on_read(move |c, _| {
+        let status = remote_.status.lock().unwrap().clone();
+        c.set_value(&serde_json::to_vec(&status).unwrap_or_default());
+    });
+
+    let remote_ = remote.clone();
+    let command_characteristic = service.lock().create_characteristic(
+        COMMAND_ID,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_ENC,
+    );
+    command_characteristic.lock().on_write(move |args| {
+        let cmd = match Command::parse(args.recv_data()) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                log::error!("Invalid remote command: {:?}", e);
+                args.reject();
+                return;
+            }
+        };
+        log::info!("Remote command: {:?}", cmd);
+        let evt = match cmd {
+            Command::Volume { value } => {
+                crate::audio::set_volume(value);
+                remote_.update(|s| s.volume = crate::audio::volume());
+                save_volume(&setting);
+                return;
+            }
+            Command::Mute { value } => {
+                crate::audio::set_muted(value);
+                remote_.update(|s| s.muted = value);
+                return;
+            }
+            Command::PushToTalk if !args.desc().bonded() => {
+                log::warn!("Push to talk needs a bonded remote");
+                args.reject();
+                return;
+            }
+            Command::PushToTalk => crate::app::Event::K0_,
+            Command::Idle => crate::app::Event::IDLE,
+        };
+        if let Err(e) = evt_tx.try_send(crate::app::Event::Event(evt)) {
+            log::error!("Failed to send remote event: {:?}", e);
+            args.reject();
+        }
+    });
+
+    ble_advertising.lock().set_data(
+        BLEAdvertisementData::new()
+            .name(&format!("EchoKit-{}", ble_addr))
+            .add_service_uuid(REMOTE_SERVICE_ID),
+    )?;
+    ble_advertising.lock().start()?;
+    Ok(remote)
+}
+
+fn save_volume(setting: &crate::bt::SharedSetting) {
+    let volume = crate::settings::Value::Integer(crate::audio::volume() as i64);
+    let r = crate::settings::def("volume")
+        .and_then(|def| crate::bt::save_setting(&mut setting.lock().unwrap(), def, &volume));
+    if let Err(e) = r {
+        log::error!("Failed to save volume: {:?}", e);
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use crate::assets::SharedAssets;
+
+/// The server `Action` that asks for a reset. It has to be confirmed on the
+/// device by holding K0 within `CONFIRM_TIMEOUT`, a short press cancels it.
+pub const ACTION: &str = "factory_reset";
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+/// What has to be written to the BLE characteristic, so a stray write can't
+/// wipe the device.
+pub const CONFIRM: &[u8] = b"factory_reset";
+/// How long K0 has to stay down at boot, after the setup page prompt.
+pub const HOLD: Duration = Duration::from_secs(10);
+
+/// Clears every setting and saved network in NVS and every stored asset
+/// (custom hello audio, backgrounds, sounds), then restarts. With no networks
+/// left the device comes back up in setup mode.
+pub fn factory_reset(assets: &SharedAssets) -> ! {
+    log::warn!("Factory reset");
+    let mut gui = crate::ui::UI::new(None)
+        .map_err(|e| log::error!("Failed to show factory reset: {:?}", e))
+        .ok();
+    let mut show = |state: &str, text: &str| {
+        if let Some(gui) = gui.as_mut() {
+            gui.state = state.to_string();
+            gui.text = text.to_string();
+            let _ = gui.display_flush();
+        }
+    };
+    show("Factory reset", "Clearing settings and assets...");
+
+    let mut errors = Vec::new();
+    if let Err(e) = assets.lock().unwrap().clear() {
+        log::error!("Failed to clear assets: {:?}", e);
+        errors.push(e.to_string());
+    }
+    // the whole partition, so BLE bonds and the WiFi driver's state go too
+    if let Err(e) = esp_idf_svc::sys::esp!(unsafe { esp_idf_svc::sys::nvs_flash_erase() }) {
+        log::error!("Failed to erase NVS: {:?}", e);
+        errors.push(e.to_string());
+    }
+
+    if errors.is_empty() {
+        show("Factory reset done", "Restarting into setup...");
+    } else {
+        show("Factory reset failed", &errors.join("\n"));
+    }
+    std::thread::sleep(Duration::from_secs(3));
+    unsafe { esp_idf_svc::sys::esp_restart() }
+}
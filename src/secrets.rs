@@ -0,0 +1,46 @@
+pub use echokit_core::secrets::*;
+
+/// NVS as the settings see it.
+pub type SealedNvs = Sealed<crate::settings::Nvs>;
+
+/// Hashed with the eFuse key to get the sealing key.
+const KEY_LABEL: &[u8] = b"echokit settings";
+
+/// Derives the sealing key with the HMAC peripheral from an eFuse key that
+/// software can't read back.
+///
+/// Without a key block for it, a random key is only burned into a free one
+/// when the firmware is built with the `efuse-seal-key` feature, as it can't
+/// be undone. Otherwise there is no key and secrets are stored unsealed.
+pub fn device_key() -> anyhow::Result<[u8; 32]> {
+    use esp_idf_svc::sys::*;
+
+    let purpose = esp_efuse_purpose_t_ESP_EFUSE_KEY_PURPOSE_HMAC_UP;
+    let mut block = esp_efuse_block_t_EFUSE_BLK_KEY_MAX;
+    if !unsafe { esp_efuse_find_purpose(purpose, &mut block) } {
+        if !cfg!(feature = "efuse-seal-key") {
+            anyhow::bail!(
+                "No eFuse sealing key, build with the efuse-seal-key feature to burn one"
+            );
+        }
+        block = unsafe { esp_efuse_find_unused_key_block() };
+        if block == esp_efuse_block_t_EFUSE_BLK_KEY_MAX {
+            anyhow::bail!("No free eFuse key block for the sealing key");
+        }
+        let mut key = [0u8; 32];
+        unsafe { esp_fill_random(key.as_mut_ptr() as _, key.len()) };
+        esp!(unsafe { esp_efuse_write_key(block, purpose, key.as_ptr() as _, key.len()) })?;
+        log::warn!("Burned the sealing key into eFuse block {}", block);
+    }
+
+    let mut key = [0u8; 32];
+    esp!(unsafe {
+        esp_hmac_calculate(
+            block - esp_efuse_block_t_EFUSE_BLK_KEY0,
+            KEY_LABEL.as_ptr() as _,
+            KEY_LABEL.len(),
+            key.as_mut_ptr(),
+        )
+    })?;
+    Ok(key)
+}
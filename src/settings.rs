@@ -0,0 +1,48 @@
+pub use echokit_core::settings::*;
+
+/// Settings in NVS, typed the way they always were: text as str, integers as
+/// i64 and bools as u8.
+pub struct Nvs(pub esp_idf_svc::nvs::EspDefaultNvs);
+
+impl Storage for Nvs {
+    fn get(&self, key: &str, kind: Kind) -> anyhow::Result<Option<Value>> {
+        let value = match kind {
+            Kind::Text { max_len } => {
+                let mut buf = vec![0; max_len + 1];
+                self.0
+                    .get_str(key, &mut buf)?
+                    .map(|s| Value::Text(s.to_string()))
+            }
+            Kind::Integer { .. } => self.0.get_i64(key)?.map(Value::Integer),
+            Kind::Bool => self.0.get_u8(key)?.map(|b| Value::Bool(b != 0)),
+        };
+        Ok(value)
+    }
+
+    fn set(&mut self, key: &str, value: &Value) -> anyhow::Result<()> {
+        match value {
+            Value::Text(s) => self.0.set_str(key, s)?,
+            Value::Integer(i) => self.0.set_i64(key, *i)?,
+            Value::Bool(b) => self.0.set_u8(key, *b as u8)?,
+        }
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(len) = self.0.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut data = vec![0; len];
+        Ok(self.0.get_blob(key, &mut data)?.map(|b| b.to_vec()))
+    }
+
+    fn set_blob(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.0.set_blob(key, data)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        self.0.remove(key)?;
+        Ok(())
+    }
+}
@@ -151,8 +151,18 @@ pub fn flush_display(color_data: &[u8], x_start: i32, y_start: i32, x_end: i32,
     }
 }
 
-pub fn backgroud(gif: &[u8]) -> Result<(), std::convert::Infallible> {
-    let image = tinygif::Gif::<ColorFormat>::from_slice(gif).unwrap();
+pub fn check_gif(gif: &[u8]) -> anyhow::Result<()> {
+    let image = tinygif::Gif::<ColorFormat>::from_slice(gif)
+        .map_err(|e| anyhow::anyhow!("Failed to parse GIF: {:?}", e))?;
+    if image.frames().next().is_none() {
+        anyhow::bail!("GIF has no frames");
+    }
+    Ok(())
+}
+
+pub fn backgroud(gif: &[u8]) -> anyhow::Result<()> {
+    let image = tinygif::Gif::<ColorFormat>::from_slice(gif)
+        .map_err(|e| anyhow::anyhow!("Failed to parse GIF: {:?}", e))?;
 
     // Create a new framebuffer
     let mut display = Box::new(Framebuffer::<
@@ -270,6 +280,8 @@ pub struct UI {
     pub text: String,
     text_area: Rectangle,
     text_background: Vec<Pixel<ColorFormat>>,
+    /// progress bar at the bottom of the text area, 0.0 ..= 1.0
+    pub progress: Option<f32>,
 
     display: Box<
         Framebuffer<
@@ -443,6 +455,7 @@ impl UI {
             state_background: state_pixels,
             text: String::new(),
             text_background: box_pixels,
+            progress: None,
             display,
             state_area,
             text_area,
@@ -490,6 +503,31 @@ impl UI {
         );
         text_box.draw(self.display.as_mut())?;
 
+        if let Some(progress) = self.progress {
+            let width = self.text_area.size.width - 40;
+            let bar = Rectangle::new(
+                self.text_area.top_left + Point::new(20, self.text_area.size.height as i32 - 40),
+                Size::new(width, 12),
+            );
+            bar.into_styled(
+                PrimitiveStyleBuilder::new()
+                    .stroke_color(ColorFormat::CSS_WHEAT)
+                    .stroke_width(1)
+                    .build(),
+            )
+            .draw(self.display.as_mut())?;
+            Rectangle::new(
+                bar.top_left,
+                Size::new((width as f32 * progress.clamp(0.0, 1.0)) as u32, 12),
+            )
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(ColorFormat::CSS_WHEAT)
+                    .build(),
+            )
+            .draw(self.display.as_mut())?;
+        }
+
         for i in 0..5 {
             let e = flush_area::<COLOR_WIDTH>(
                 self.display.data(),
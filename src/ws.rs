@@ -5,51 +5,168 @@ fn print_stack_high() {
     log::info!("Stack high: {}", stack_high);
 }
 
-use crate::{app::Event, protocol::ServerEvent};
+use crate::{
+    app::Event,
+    protocol::{ClientEvent, Encoding, ServerEvent},
+};
 use futures_util::{SinkExt, StreamExt, TryFutureExt};
 use tokio_websockets::Message;
 
+type WsStream =
+    tokio_websockets::WebSocketStream<tokio_websockets::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Connection to the server that survives drops: once the socket fails,
+/// `recv` reports [`Event::ServerDisconnected`] and `reconnect` opens a new
+/// one.
 pub struct Server {
     pub uri: String,
+    pub encoding: Encoding,
+    // `token` and `language` from the settings, sent on every connect
+    token: String,
+    language: String,
     timeout: std::time::Duration,
-    ws: tokio_websockets::WebSocketStream<tokio_websockets::MaybeTlsStream<tokio::net::TcpStream>>,
+    ws: Option<WsStream>,
+    // the socket failed on send, recv still has to report it
+    lost: bool,
 }
 
 impl Server {
-    pub async fn new(uri: String) -> anyhow::Result<Self> {
-        let (ws, _resp) = tokio_websockets::ClientBuilder::new()
-            .uri(&uri)?
-            .connect()
-            .await?;
-
+    pub async fn new(uri: String, settings: &crate::settings::Settings) -> anyhow::Result<Self> {
+        let token = settings.token.clone();
+        let language = settings.language.clone();
+        let (ws, encoding) = Self::connect(&uri, &token, &language).await?;
         let timeout = std::time::Duration::from_secs(30);
 
-        Ok(Self { uri, timeout, ws })
+        Ok(Self {
+            uri,
+            encoding,
+            token,
+            language,
+            timeout,
+            ws: Some(ws),
+            lost: false,
+        })
+    }
+
+    async fn connect(
+        uri: &str,
+        token: &str,
+        language: &str,
+    ) -> anyhow::Result<(WsStream, Encoding)> {
+        // Offer both encodings, msgpack first. The server answers with the one it wants.
+        let offer = [Encoding::MsgPack, Encoding::Json]
+            .map(|e| e.subprotocol())
+            .join(", ");
+
+        let mut builder = tokio_websockets::ClientBuilder::new().uri(uri)?.add_header(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            http::HeaderValue::from_str(&offer)?,
+        );
+        if !token.is_empty() {
+            let mut value = http::HeaderValue::from_str(&format!("Bearer {}", token))?;
+            value.set_sensitive(true);
+            builder = builder.add_header(http::header::AUTHORIZATION, value);
+        }
+        if !language.is_empty() {
+            builder = builder.add_header(
+                http::header::ACCEPT_LANGUAGE,
+                http::HeaderValue::from_str(language)?,
+            );
+        }
+        let (ws, resp) = builder.connect().await?;
+
+        let encoding = resp
+            .headers()
+            .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Encoding::from_subprotocol)
+            .unwrap_or(Encoding::MsgPack);
+        log::info!("Server encoding: {:?}", encoding);
+
+        Ok((ws, encoding))
+    }
+
+    pub async fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.ws = None;
+        let (ws, encoding) = tokio::time::timeout(
+            self.timeout,
+            Self::connect(&self.uri, &self.token, &self.language),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Timeout connecting to server"))??;
+        self.ws = Some(ws);
+        self.encoding = encoding;
+        self.lost = false;
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.ws.is_some()
+    }
+
+    /// Drops the socket, e.g. when Wi-Fi is gone and it can't recover.
+    pub fn disconnect(&mut self) {
+        if self.ws.take().is_some() {
+            self.lost = true;
+        }
     }
 
     pub fn set_timeout(&mut self, timeout: std::time::Duration) {
         self.timeout = timeout;
     }
 
+    /// Sends `msg`, or drops it when the connection is down. A failed send
+    /// closes the connection and is reported by the next `recv`.
     pub async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
-        tokio::time::timeout(self.timeout, self.ws.send(msg))
+        let Some(ws) = self.ws.as_mut() else {
+            log::warn!("Not connected, message dropped");
+            return Ok(());
+        };
+        let r = tokio::time::timeout(self.timeout, ws.send(msg))
             .map_err(|_| anyhow::anyhow!("Timeout sending message"))
-            .await??;
+            .await
+            .and_then(|r| Ok(r?));
+        if let Err(e) = r {
+            log::error!("Failed to send to server: {:?}", e);
+            self.disconnect();
+        }
         Ok(())
     }
 
+    pub async fn send_event(&mut self, evt: &ClientEvent) -> anyhow::Result<()> {
+        self.send(Message::text(evt.to_json()?)).await
+    }
+
+    /// Waits for the next server event. Pends while disconnected.
     pub async fn recv(&mut self) -> anyhow::Result<Event> {
-        let msg = self
-            .ws
-            .next()
-            .await
-            .ok_or_else(|| anyhow::anyhow!("WS channel closed"))??;
+        if std::mem::take(&mut self.lost) {
+            return Ok(Event::ServerDisconnected);
+        }
+        let Some(ws) = self.ws.as_mut() else {
+            return std::future::pending().await;
+        };
+        let msg = loop {
+            match ws.next().await {
+                // answered by tokio-websockets already
+                Some(Ok(msg)) if msg.is_ping() || msg.is_pong() => continue,
+                Some(Ok(msg)) => break msg,
+                r => {
+                    log::error!("WS channel closed: {:?}", r);
+                    self.ws = None;
+                    return Ok(Event::ServerDisconnected);
+                }
+            }
+        };
 
-        if msg.is_binary() {
-            let payload = msg.into_payload();
-            let evt = rmp_serde::from_slice::<ServerEvent>(&payload)
-                .map_err(|e| anyhow::anyhow!("Failed to deserialize binary data: {}", e))?;
+        // Data frames have to be in the encoding the server picked
+        if msg.is_binary() || msg.is_text() {
+            let binary = msg.is_binary();
+            let evt = self.encoding.decode(&msg.into_payload(), binary)?;
             Ok(Event::ServerEvent(evt))
+        } else if msg.is_close() {
+            log::info!("Server closed the connection");
+            self.ws = None;
+            Ok(Event::ServerDisconnected)
         } else {
             Err(anyhow::anyhow!("Invalid message type"))
         }